@@ -3,7 +3,8 @@
 use opencv::core::{Mat};
 use opencv::prelude::MatTrait;
 use rand::Rng;
-use std::error::Error;
+
+use crate::error::HdrError;
 
 // This file contains some helper functions of math
 // It is designed and implemented following c coding style.
@@ -29,21 +30,41 @@ pub fn hat(val: f32,
     }
 }
 
+// Weights of every pixel value of an image with the given
+// number of levels, e.g. 256 for 8-bit images.
+pub fn generate_hat_weights(levels: usize) -> Vec<f32> {
+    let max_val = (levels - 1) as f32;
+    let mut weights: Vec<f32> = Vec::with_capacity(levels);
+    for i in 0..levels {
+        weights.push(hat(i as f32, 0.0, max_val));
+    }
+
+    weights
+}
+
+// Map a pixel value of an image with the given number of levels to one
+// of bin_num bins, e.g. a 16-bit value to the 8-bit value closest to it.
+pub fn bin_pixel_value(z: usize,
+                       levels: usize,
+                       bin_num: usize) -> usize {
+    (z * (bin_num - 1) + (levels - 1) / 2) / (levels - 1)
+}
+
 #[allow(dead_code)]
 pub fn get_translation_matrix(dst: &mut Mat,
                               tx: i32,
                               ty: i32)
-    -> Result<(), Box<dyn Error>> {
+    -> Result<(), HdrError> {
 
     unsafe {
         dst.create_rows_cols(2, 3, opencv::core::CV_32FC1)?;
     }
-    *dst.at_2d_mut::<f32>(0, 0).unwrap() = 1.0;
-    *dst.at_2d_mut::<f32>(0, 1).unwrap() = 0.0;
-    *dst.at_2d_mut::<f32>(0, 2).unwrap() = tx as f32;
-    *dst.at_2d_mut::<f32>(1, 0).unwrap() = 0.0;
-    *dst.at_2d_mut::<f32>(1, 1).unwrap() = 1.0;
-    *dst.at_2d_mut::<f32>(1, 2).unwrap() = ty as f32;
+    *dst.at_2d_mut::<f32>(0, 0)? = 1.0;
+    *dst.at_2d_mut::<f32>(0, 1)? = 0.0;
+    *dst.at_2d_mut::<f32>(0, 2)? = tx as f32;
+    *dst.at_2d_mut::<f32>(1, 0)? = 0.0;
+    *dst.at_2d_mut::<f32>(1, 1)? = 1.0;
+    *dst.at_2d_mut::<f32>(1, 2)? = ty as f32;
 
     Ok(())
 }
@@ -0,0 +1,8 @@
+/* Copyright 2020 Yuchen Wong */
+
+// Helper functions shared by all the algorithms.
+
+pub mod math_utils;
+pub mod opencv_utils;
+#[cfg(test)]
+pub mod test_utils;
@@ -1,28 +1,36 @@
 /* Copyright 2020 Yuchen Wong */
 
-use opencv::core::{CV_8UC1, Mat, MatExprTrait, Size_, Vec3b};
+use opencv::core::{CV_8UC1, CV_16UC1, Mat, MatExprTrait, Size_, Vec3b, Vec3f, Vec3w};
 use opencv::imgcodecs::{imwrite};
 use opencv::prelude::{MatTrait, Vector};
-use opencv::types::{VectorOfi32};
-use std::error::Error;
+use opencv::types::{VectorOfMat, VectorOfi32};
+use std::fs;
+
+use crate::error::HdrError;
 
 // This file contains some helper function of opencv
 // It is designed and implemented following c coding style.
 
+// Mark the pixels close to the median as 0 and the others as 255 in a
+// CV_8UC1 image. offset is given in 8-bit units and scaled for 16-bit images.
 #[allow(dead_code)]
 pub fn compute_exclusive_image(src: &Mat, 
                          dst: &mut Mat,
                          offset: u8) 
-    -> Result<(), Box<dyn Error>> {
+    -> Result<(), HdrError> {
     let rows = src.rows();
     let cols = src.cols();
 
-    cvt_rgb_image_to_grey(src, dst)?;
+    let mut grey_image: Mat = Mat::default()?;
+    cvt_rgb_image_to_grey(src, &mut grey_image)?;
+    let levels = pixel_levels(src)?;
+    let offset: u32 = offset as u32 * ((levels - 1) / 255) as u32;
+    let max_value: u32 = (levels - 1) as u32;
 
-    let median_pixel_value = find_median(dst);
+    let median_pixel_value = find_median(&grey_image)? as u32;
     let high_bound;
-    if 255 - median_pixel_value < offset {
-        high_bound = 255;
+    if max_value - median_pixel_value < offset {
+        high_bound = max_value;
     } else {
         high_bound = median_pixel_value + offset;
     }
@@ -32,59 +40,85 @@ pub fn compute_exclusive_image(src: &Mat,
     } else {
         low_bound = median_pixel_value - offset;
     }
+
+    unsafe {
+        dst.create_rows_cols(rows, cols, CV_8UC1)?;
+    }
     for i in 0..rows {
         for j in 0..cols {
-            let pixel_value: u8 = *dst.at_2d::<u8>(i, j).unwrap();
+            let pixel_value: u32 = get_grey_value(&grey_image, i, j, levels)?;
             if pixel_value <=high_bound && pixel_value >= low_bound {
-                *dst.at_2d_mut::<u8>(i, j).unwrap() = 0;
+                *dst.at_2d_mut::<u8>(i, j)? = 0;
             } else {
-                *dst.at_2d_mut::<u8>(i, j).unwrap() = 255;
+                *dst.at_2d_mut::<u8>(i, j)? = 255;
             }
         }
     }
     Ok(())
 }
 
+// The median threshold bitmap of an 8-bit or 16-bit image, as a CV_8UC1 image.
 #[allow(dead_code)]
 pub fn compute_mtb_image(src: &Mat, 
                          dst: &mut Mat) 
-    -> Result<(), Box<dyn Error>> {
+    -> Result<(), HdrError> {
     let rows = src.rows();
     let cols = src.cols();
 
-    cvt_rgb_image_to_grey(src, dst)?;
+    let mut grey_image: Mat = Mat::default()?;
+    cvt_rgb_image_to_grey(src, &mut grey_image)?;
+    let levels = pixel_levels(src)?;
 
-    let median_pixel_value = find_median(dst);
+    let median_pixel_value = find_median(&grey_image)? as u32;
+    unsafe {
+        dst.create_rows_cols(rows, cols, CV_8UC1)?;
+    }
     for i in 0..rows {
         for j in 0..cols {
-            let pixel_value: u8 = *dst.at_2d::<u8>(i, j).unwrap();
+            let pixel_value: u32 = get_grey_value(&grey_image, i, j, levels)?;
             if pixel_value > median_pixel_value {
-                *dst.at_2d_mut::<u8>(i, j).unwrap() = 255;
+                *dst.at_2d_mut::<u8>(i, j)? = 255;
             } else {
-                *dst.at_2d_mut::<u8>(i, j).unwrap() = 0;
+                *dst.at_2d_mut::<u8>(i, j)? = 0;
             }
         }
     }
     Ok(())
 }
 
+// Convert a CV_8UC3 image to CV_8UC1, or a CV_16UC3 image to CV_16UC1.
 #[allow(dead_code)]
 pub fn cvt_rgb_image_to_grey(src: &Mat,
-                         dst: &mut Mat) -> Result<(), Box<dyn Error>> {
+                         dst: &mut Mat) -> Result<(), HdrError> {
+    let levels = pixel_levels(src)?;
+
     let rows = src.rows();
     let cols = src.cols();
 
+    if levels > 256 {
+        unsafe {
+            dst.create_rows_cols(rows, cols, CV_16UC1)?;
+        }
+        for i in 0..rows {
+            for j in 0..cols {
+                let pixel_value: Vec3w = *src.at_2d::<Vec3w>(i, j)?;
+                *dst.at_2d_mut::<u16>(i, j)? = mix_rgb_to_gray_16u(pixel_value[0], pixel_value[1], pixel_value[2]);
+            }
+        }
+        return Ok(());
+    }
+
     unsafe {
         dst.create_rows_cols(rows, cols, CV_8UC1)?;
     }
 
     for i in 0..rows {
         for j in 0..cols {
-            let pixel_value: Vec3b = *src.at_2d::<Vec3b>(i, j).unwrap();
+            let pixel_value: Vec3b = *src.at_2d::<Vec3b>(i, j)?;
             let pixel_b = pixel_value[0] as u16;
             let pixel_g = pixel_value[1] as u16;
             let pixel_r = pixel_value[2] as u16;
-            *dst.at_2d_mut::<u8>(i, j).unwrap() = mix_rgb_to_gray(pixel_b, pixel_g, pixel_r);
+            *dst.at_2d_mut::<u8>(i, j)? = mix_rgb_to_gray(pixel_b, pixel_g, pixel_r);
         }
     }
 
@@ -95,7 +129,7 @@ pub fn cvt_rgb_image_to_grey(src: &Mat,
 pub fn resize_image_with_default(src: &Mat,
                                  dst: &mut Mat,
                                  fx: f64,
-                                 fy: f64) -> Result<(), Box<dyn Error>> {
+                                 fy: f64) -> Result<(), HdrError> {
     opencv::imgproc::resize(src, dst, Size_::default(), 
                             fx, fy, opencv::imgproc::INTER_LINEAR)?;
     Ok(())
@@ -104,7 +138,7 @@ pub fn resize_image_with_default(src: &Mat,
 #[allow(dead_code)]
 pub fn warp_affine_with_default(src: &Mat,
                                 dst: &mut Mat,
-                                transform: &Mat) -> Result<(), Box<dyn Error>> {
+                                transform: &Mat) -> Result<(), HdrError> {
     opencv::imgproc::warp_affine(src, dst, transform, Size_::default(), 
                 opencv::imgproc::INTER_LINEAR, 
                 opencv::core::BORDER_CONSTANT,
@@ -116,7 +150,12 @@ pub fn warp_affine_with_default(src: &Mat,
 pub fn matmul(a: &Mat,
               b: &Mat,
               dtype: i32,
-              out_result: &mut Mat) -> Result<(), Box<dyn Error>> {
+              out_result: &mut Mat) -> Result<(), HdrError> {
+    if a.cols() != b.rows() {
+        return Err(HdrError::InvalidArgument(format!(
+            "Cannot multiply a {}x{} matrix by a {}x{} matrix.", a.rows(), a.cols(), b.rows(), b.cols())));
+    }
+
     let rows = a.rows();
     let cols = b.cols();
 
@@ -126,32 +165,34 @@ pub fn matmul(a: &Mat,
 
     log::info!("{} {}", rows, cols);
     for i in 0..rows {
-        let cur_row: Mat = a.row(i).unwrap().t().unwrap().to_mat().unwrap();
+        let cur_row: Mat = a.row(i)?.t()?.to_mat()?;
         for j in 0..cols {
-            *out_result.at_2d_mut::<f32>(i, j).unwrap() = 
-                cur_row.dot(&b.col(j).unwrap()).unwrap() as f32;
+            *out_result.at_2d_mut::<f32>(i, j)? =
+                cur_row.dot(&b.col(j)?)? as f32;
         }
     }
 
     Ok(())
 }
 
+// The median of a CV_8UC1 or CV_16UC1 image.
 #[allow(dead_code)]
-pub fn find_median(img: &Mat) -> u8 {
-    let mut pixel_hist: [i32; 256] = [0; 256];
+pub fn find_median(img: &Mat) -> Result<u16, HdrError> {
+    let levels: usize = if img.depth()? == opencv::core::CV_16U { 65536 } else { 256 };
+    let mut pixel_hist: Vec<i32> = vec![0; levels];
 
     for i in 0..img.rows() {
         for j in 0..img.cols() {
-            let pixel_value: u8 = *img.at_2d::<u8>(i, j).unwrap();
+            let pixel_value = get_grey_value(img, i, j, levels)?;
             pixel_hist[pixel_value as usize] += 1;
         }
     }
 
     let median_index: i32 = (img.rows() * img.cols()) >> 1;
     let mut cur_pixel_count: i32 = 0;
-    let mut res = 255;
+    let mut res = levels - 1;
 
-    for i in 0..256 {
+    for i in 0..levels {
         cur_pixel_count += pixel_hist[i];
         if cur_pixel_count >= median_index {
             res = i;
@@ -159,13 +200,13 @@ pub fn find_median(img: &Mat) -> u8 {
         }
     }
 
-    return res as u8;
+    Ok(res as u16)
 }
 
 // Save .exr file to a given path
 #[allow(dead_code)]
 pub fn save_exr_with_default(path: &String,
-                             image: &Mat) -> Result<(), Box<dyn Error>> {
+                             image: &Mat) -> Result<(), HdrError> {
     let mut options: VectorOfi32 = VectorOfi32::new();
     options.push(opencv::imgcodecs::IMWRITE_EXR_TYPE_FLOAT);
     imwrite(path, &image, &options)?;
@@ -173,6 +214,228 @@ pub fn save_exr_with_default(path: &String,
     Ok(())
 }
 
+// Save a CV_32FC1 or CV_32FC3 image as a Portable Float Map, lossless and
+// readable without OpenEXR. Rows are written bottom to top in RGB order,
+// as the format requires.
+pub fn save_pfm(path: &str,
+                image: &Mat,
+                little_endian: bool) -> Result<(), HdrError> {
+    let typ = image.typ()?;
+    let channels: i32 = match typ {
+        opencv::core::CV_32FC1 => 1,
+        opencv::core::CV_32FC3 => 3,
+        _ => return Err(HdrError::InvalidMatType { expected: opencv::core::CV_32FC3, actual: typ }),
+    };
+    let rows = image.rows();
+    let cols = image.cols();
+
+    let mut data: Vec<u8> = Vec::with_capacity(rows as usize * cols as usize * channels as usize * 4 + 32);
+    let magic = if channels == 3 { "PF" } else { "Pf" };
+    let scale = if little_endian { "-1.0" } else { "1.0" };
+    data.extend_from_slice(format!("{}\n{} {}\n{}\n", magic, cols, rows, scale).as_bytes());
+
+    for i in (0..rows).rev() {
+        for j in 0..cols {
+            for c in (0..channels).rev() {
+                let value: f32 = if channels == 3 {
+                    image.at_2d::<Vec3f>(i, j)?[c as usize]
+                } else {
+                    *image.at_2d::<f32>(i, j)?
+                };
+                if little_endian {
+                    data.extend_from_slice(&value.to_le_bytes());
+                } else {
+                    data.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+        }
+    }
+
+    fs::write(path, &data)?;
+    Ok(())
+}
+
+// Load a Portable Float Map as CV_32FC3 (BGR) or CV_32FC1 depending on
+// the file, in either byte order.
+pub fn load_pfm(path: &str,
+                out_image: &mut Mat) -> Result<(), HdrError> {
+    let data: Vec<u8> = fs::read(path)?;
+    let mut pos: usize = 0;
+    let (cols, rows, channels, little_endian) = read_pfm_header(&data, &mut pos)
+        .map_err(|message| HdrError::InvalidFormat(format!("{}: {}", path, message)))?;
+
+    let typ = if channels == 3 { opencv::core::CV_32FC3 } else { opencv::core::CV_32FC1 };
+    unsafe {
+        out_image.create_rows_cols(rows as i32, cols as i32, typ)?;
+    }
+
+    for i in (0..rows as i32).rev() {
+        for j in 0..cols as i32 {
+            for c in (0..channels).rev() {
+                let bytes = [data[pos], data[pos + 1], data[pos + 2], data[pos + 3]];
+                let value = if little_endian { f32::from_le_bytes(bytes) } else { f32::from_be_bytes(bytes) };
+                pos += 4;
+                if channels == 3 {
+                    out_image.at_2d_mut::<Vec3f>(i, j)?[c] = value;
+                } else {
+                    *out_image.at_2d_mut::<f32>(i, j)? = value;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Parse the header of a Portable Float Map, returning (cols, rows,
+// channels, little_endian) with pos at the first pixel. The size of
+// the pixels is checked against the data, and the dimensions against
+// what a Mat can hold.
+fn read_pfm_header(data: &[u8],
+                   pos: &mut usize) -> Result<(usize, usize, usize, bool), String> {
+    // The header is made of 4 tokens, magic, width, height and scale,
+    // followed by a single whitespace before the pixels.
+    let mut tokens: Vec<String> = Vec::with_capacity(4);
+    while tokens.len() < 4 {
+        while *pos < data.len() && data[*pos].is_ascii_whitespace() {
+            *pos += 1;
+        }
+        let start = *pos;
+        while *pos < data.len() && !data[*pos].is_ascii_whitespace() {
+            *pos += 1;
+        }
+        if start == *pos {
+            return Err(String::from("truncated header."));
+        }
+        tokens.push(String::from_utf8_lossy(&data[start..*pos]).to_string());
+    }
+    *pos += 1;
+
+    let channels: usize = match tokens[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(String::from("unknown magic number.")),
+    };
+    let cols: usize = tokens[1].parse().map_err(|_| String::from("invalid width."))?;
+    let rows: usize = tokens[2].parse().map_err(|_| String::from("invalid height."))?;
+    let scale: f32 = tokens[3].parse().map_err(|_| String::from("invalid scale."))?;
+    if cols == 0 || rows == 0 || scale == 0.0 {
+        return Err(String::from("invalid header."));
+    }
+    if cols > i32::MAX as usize || rows > i32::MAX as usize {
+        return Err(format!("image size {}x{} is too large.", cols, rows));
+    }
+
+    let size = rows.checked_mul(cols)
+        .and_then(|pixels| pixels.checked_mul(channels * 4))
+        .ok_or_else(|| format!("image size {}x{} is too large.", cols, rows))?;
+    if data.len() < *pos || data.len() - *pos < size {
+        return Err(String::from("truncated pixels."));
+    }
+
+    Ok((cols, rows, channels, scale < 0.0))
+}
+
+// Check that a Mat has the given type, e.g. CV_8UC3.
+pub fn check_mat_type(image: &Mat,
+                      expected: i32) -> Result<(), HdrError> {
+    let actual = image.typ()?;
+    if actual != expected {
+        return Err(HdrError::InvalidMatType { expected: expected, actual: actual });
+    }
+
+    Ok(())
+}
+
+// Check that a set of images is not empty and all images
+// share the type and size of the first one.
+pub fn check_images(images: &VectorOfMat,
+                    expected_type: i32) -> Result<(), HdrError> {
+    if images.len() == 0 {
+        return Err(HdrError::EmptyImages);
+    }
+
+    let first_image: Mat = images.get(0)?;
+    let expected_size = (first_image.rows(), first_image.cols());
+    for i in 0..images.len() {
+        let cur_image: Mat = images.get(i)?;
+        check_mat_type(&cur_image, expected_type)?;
+        let cur_size = (cur_image.rows(), cur_image.cols());
+        if cur_size != expected_size {
+            return Err(HdrError::MismatchedImageSize { expected: expected_size, actual: cur_size });
+        }
+    }
+
+    Ok(())
+}
+
+// Number of pixel values of a CV_8UC3 (256) or CV_16UC3 (65536) image.
+pub fn pixel_levels(image: &Mat) -> Result<usize, HdrError> {
+    match image.typ()? {
+        opencv::core::CV_8UC3 => Ok(256),
+        opencv::core::CV_16UC3 => Ok(65536),
+        actual => Err(HdrError::InvalidMatType { expected: opencv::core::CV_8UC3, actual: actual }),
+    }
+}
+
+// Check that the images of a bracket are all CV_8UC3 or all CV_16UC3
+// with the same size, and return their number of pixel values.
+pub fn check_ldr_images(images: &VectorOfMat) -> Result<usize, HdrError> {
+    if images.len() == 0 {
+        return Err(HdrError::EmptyImages);
+    }
+
+    let first_image: Mat = images.get(0)?;
+    let levels = pixel_levels(&first_image)?;
+    check_images(images, first_image.typ()?)?;
+
+    Ok(levels)
+}
+
+// Read one channel of a CV_8UC3 or CV_16UC3 pixel, levels telling which one.
+pub fn get_channel_value(image: &Mat,
+                         row: i32,
+                         col: i32,
+                         channel: usize,
+                         levels: usize) -> Result<usize, HdrError> {
+    if levels > 256 {
+        Ok(image.at_2d::<Vec3w>(row, col)?[channel] as usize)
+    } else {
+        Ok(image.at_2d::<Vec3b>(row, col)?[channel] as usize)
+    }
+}
+
+fn get_grey_value(image: &Mat,
+                  row: i32,
+                  col: i32,
+                  levels: usize) -> Result<u32, HdrError> {
+    if levels > 256 {
+        Ok(*image.at_2d::<u16>(row, col)? as u32)
+    } else {
+        Ok(*image.at_2d::<u8>(row, col)? as u32)
+    }
+}
+
+// Check that every image of a bracket has a positive exposure time.
+pub fn check_exposures(images: &VectorOfMat,
+                       shutter_speeds: &Vec<f32>) -> Result<(), HdrError> {
+    if shutter_speeds.len() == 0 {
+        return Err(HdrError::EmptyExposures);
+    }
+    if shutter_speeds.len() != images.len() {
+        return Err(HdrError::MismatchedExposureCount {
+            images: images.len(), exposures: shutter_speeds.len() });
+    }
+    for shutter_speed in shutter_speeds {
+        if *shutter_speed <= 0.0 {
+            return Err(HdrError::InvalidArgument(
+                format!("Exposure time must be positive, got {}.", shutter_speed)));
+        }
+    }
+
+    Ok(())
+}
+
 // We use the RGB to Gray mapping function
 // described in Greg's algorithm.
 #[allow(dead_code)]
@@ -180,10 +443,85 @@ fn mix_rgb_to_gray(b: u16, g: u16, r: u16) -> u8 {
     return ((19 * b + 183 * g + 54 * r) >> 8) as u8; 
 }
 
-pub fn get_pixel<T: opencv::core::DataType>(image: &Mat, x: i32, y: i32) -> T {
-    return *image.at_2d::<T>(x, y).unwrap();
+fn mix_rgb_to_gray_16u(b: u16, g: u16, r: u16) -> u16 {
+    return ((19 * b as u32 + 183 * g as u32 + 54 * r as u32) >> 8) as u16;
+}
+
+pub fn get_pixel<T: opencv::core::DataType>(image: &Mat, x: i32, y: i32) -> Result<T, HdrError> {
+    Ok(*image.at_2d::<T>(x, y)?)
+}
+
+pub fn set_pixel<T: opencv::core::DataType>(image: &mut Mat, x: i32, y: i32, value: T) -> Result<(), HdrError> {
+    *image.at_2d_mut::<T>(x, y)? = value;
+    Ok(())
 }
 
-pub fn set_pixel<T: opencv::core::DataType>(image: &mut Mat, x: i32, y: i32, value: T) {
-    *image.at_2d_mut::<T>(x, y).unwrap() = value;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::test_utils::TempFile;
+
+    #[test]
+    fn pfm_round_trip() {
+        for &little_endian in &[true, false] {
+            let mut color: Mat = Mat::new_rows_cols_with_default(
+                3, 5, opencv::core::CV_32FC3, opencv::core::Scalar_::default()).unwrap();
+            let mut grey: Mat = Mat::new_rows_cols_with_default(
+                3, 5, opencv::core::CV_32FC1, opencv::core::Scalar_::default()).unwrap();
+            for i in 0..3 {
+                for j in 0..5 {
+                    let value = (i * 5 + j) as f32 * 0.25 - 1.0;
+                    *color.at_2d_mut::<Vec3f>(i, j).unwrap() = Vec3f::from([value, value * 2.0, 1e6 * value]);
+                    *grey.at_2d_mut::<f32>(i, j).unwrap() = value;
+                }
+            }
+
+            for (name, image) in [("color.pfm", &color), ("grey.pfm", &grey)].iter() {
+                let file = TempFile::new(name);
+                save_pfm(file.path(), image, little_endian).unwrap();
+                let mut loaded: Mat = Mat::default().unwrap();
+                load_pfm(file.path(), &mut loaded).unwrap();
+                assert_eq!(loaded.typ().unwrap(), image.typ().unwrap());
+                assert_eq!((loaded.rows(), loaded.cols()), (3, 5));
+                let mut difference: Mat = Mat::default().unwrap();
+                opencv::core::absdiff(&loaded, *image, &mut difference).unwrap();
+                let max_difference = opencv::core::norm(&difference, opencv::core::NORM_INF,
+                                                        &opencv::core::no_array().unwrap()).unwrap();
+                assert_eq!(max_difference, 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_pfm_header() {
+        let mut data: Vec<u8> = b"PF\n2 3\n-1.0\n".to_vec();
+        let header_length = data.len();
+        data.resize(header_length + 2 * 3 * 3 * 4, 0);
+        let mut pos: usize = 0;
+        assert_eq!(read_pfm_header(&data, &mut pos), Ok((2, 3, 3, true)));
+        assert_eq!(pos, header_length);
+
+        let mut data: Vec<u8> = b"Pf 4 1 1.0 ".to_vec();
+        data.resize(data.len() + 4 * 4, 0);
+        let mut pos: usize = 0;
+        assert_eq!(read_pfm_header(&data, &mut pos), Ok((4, 1, 1, false)));
+    }
+
+    #[test]
+    fn rejects_malformed_pfm_header() {
+        for data in [&b"PF\n2 3\n"[..],
+                     &b"P6\n2 3\n-1.0\n"[..],
+                     &b"PF\n2 x\n-1.0\n"[..],
+                     &b"PF\n0 3\n-1.0\n"[..],
+                     &b"PF\n2 3\n0.0\n"[..],
+                     // Fewer pixels than the header announces.
+                     &b"PF\n2 3\n-1.0\n\0\0\0\0"[..],
+                     // Dimensions a Mat can not hold.
+                     &b"Pf\n3000000000 1\n-1.0\n"[..],
+                     // The size of the pixels overflows usize.
+                     &b"PF\n2147483647 2147483647\n-1.0\n"[..]].iter() {
+            let mut pos: usize = 0;
+            assert!(read_pfm_header(data, &mut pos).is_err(), "{:?}", String::from_utf8_lossy(data));
+        }
+    }
 }
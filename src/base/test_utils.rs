@@ -0,0 +1,41 @@
+/* Copyright 2020 Yuchen Wong */
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// This file contains helpers shared by the unit tests.
+
+static TEMP_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// A file in the temporary directory, removed when dropped. Names are
+// prefixed with the process id and a counter, so that tests running
+// concurrently, in this process or another one, never share a file.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(name: &str) -> TempFile {
+        let count = TEMP_FILE_COUNT.fetch_add(1, Ordering::SeqCst);
+        let file_name = format!("hdr_rust_{}_{}_{}", std::process::id(), count, name);
+        TempFile { path: std::env::temp_dir().join(file_name) }
+    }
+
+    pub fn with_content(name: &str,
+                        content: &[u8]) -> TempFile {
+        let file = TempFile::new(name);
+        fs::write(&file.path, content).unwrap();
+        file
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
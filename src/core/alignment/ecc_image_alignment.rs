@@ -0,0 +1,227 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat, MatExprTrait, Rect, Size, TermCriteria, TermCriteria_Type};
+use opencv::prelude::{MatTrait, Vector};
+use opencv::types::VectorOfMat;
+
+use crate::base::opencv_utils::{check_ldr_images, cvt_rgb_image_to_grey, pixel_levels};
+use crate::core::alignment::mtb_image_alignment::{select_pivot, Reference};
+use crate::core::alignment::valid_region;
+use crate::error::HdrError;
+
+// Geometric transform estimated between an image and the reference.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MotionModel {
+    Translation,
+    // Rotation and translation.
+    Euclidean,
+    Affine,
+    Homography,
+}
+
+// Align a bracket with Evangelidis and Psarakis' enhanced correlation
+// coefficient maximization, which unlike MTB estimates sub-pixel motions
+// and rotations of handheld brackets.
+// Exposures are normalized by equalizing the histograms of their grey
+// images, then the transform is refined from the coarsest of max_level
+// pyramid levels to the full resolution. Every image is warped onto the
+// middle one, as MTB does.
+pub fn align(images: &VectorOfMat,
+             aligned_images: &mut VectorOfMat,
+             motion_model: MotionModel,
+             max_level: u8,
+             max_iterations: i32,
+             epsilon: f64) -> Result<(), HdrError> {
+    let mut transforms: Vec<Mat> = Vec::new();
+    estimate_transforms(images, Reference::Middle, motion_model, max_level, max_iterations, epsilon,
+                        &mut transforms)?;
+    apply_transforms(images, &transforms, aligned_images)
+}
+
+// Estimate the transform of every image onto the reference, chosen as
+// MTB does, as a 2x3 CV_32FC1 matrix, or 3x3 for homographies, mapping
+// reference coordinates to image coordinates. The pivot gets the identity.
+pub fn estimate_transforms(images: &VectorOfMat,
+                           reference: Reference,
+                           motion_model: MotionModel,
+                           max_level: u8,
+                           max_iterations: i32,
+                           epsilon: f64,
+                           out_transforms: &mut Vec<Mat>) -> Result<(), HdrError> {
+    log::trace!("Start ECC Alignment.");
+    out_transforms.clear();
+    check_ldr_images(images)?;
+    if max_level < 1 {
+        return Err(HdrError::InvalidArgument(
+            format!("ECC alignment needs at least 1 pyramid level, got {}.", max_level)));
+    }
+    if max_iterations < 1 {
+        return Err(HdrError::InvalidArgument(
+            format!("ECC alignment needs at least 1 iteration, got {}.", max_iterations)));
+    }
+
+    let pivot: usize = select_pivot(images, reference)?;
+    log::info!("Align pivot is {}.", pivot);
+
+    let mut pivot_pyramid: Vec<Mat> = Vec::new();
+    compute_normalized_pyramid(&images.get(pivot)?, max_level, &mut pivot_pyramid)?;
+
+    let criteria = TermCriteria::new(TermCriteria_Type::COUNT as i32 + TermCriteria_Type::EPS as i32,
+                                     max_iterations, epsilon)?;
+    for i in 0..images.len() {
+        if i == pivot {
+            out_transforms.push(identity_warp(motion_model)?);
+            continue;
+        }
+
+        let mut image_pyramid: Vec<Mat> = Vec::new();
+        compute_normalized_pyramid(&images.get(i)?, max_level, &mut image_pyramid)?;
+
+        let mut warp: Mat = identity_warp(motion_model)?;
+        let mut converged: bool = false;
+        for level in (0..max_level as usize).rev() {
+            if level + 1 < max_level as usize {
+                upscale_warp(&mut warp, motion_model)?;
+            }
+
+            let mut cur_warp: Mat = warp.clone()?;
+            let result = opencv::video::find_transform_ecc(&pivot_pyramid[level], &image_pyramid[level], &mut cur_warp,
+                                                           motion_type(motion_model), criteria,
+                                                           &opencv::core::no_array()?, 5);
+            match result {
+                Ok(correlation) => {
+                    log::info!("Image {} level {}: correlation {}.", i, level, correlation);
+                    warp = cur_warp;
+                    converged = true;
+                },
+                // Flat or very small levels may not converge, keep the
+                // transform of the coarser levels.
+                Err(e) => log::warn!("ECC did not converge for image {} at level {}: {}.", i, level, e),
+            }
+        }
+        // The identity is not an alignment, fail rather than keep it.
+        if !converged {
+            return Err(HdrError::InvalidArgument(
+                format!("ECC alignment of image {} did not converge at any pyramid level.", i)));
+        }
+
+        log::info!("Image {} with translation {}, {}.",
+                   i, *warp.at_2d::<f32>(0, 2)?, *warp.at_2d::<f32>(1, 2)?);
+
+        out_transforms.push(warp);
+    }
+
+    log::trace!("ECC Alignment finished.");
+    Ok(())
+}
+
+// Warp every image by its transform from estimate_transforms. The images
+// may be of any type, e.g. linear renditions of the aligned bracket.
+pub fn apply_transforms(images: &VectorOfMat,
+                        transforms: &Vec<Mat>,
+                        aligned_images: &mut VectorOfMat) -> Result<(), HdrError> {
+    if images.len() != transforms.len() {
+        return Err(HdrError::InvalidArgument(format!(
+            "Got {} transforms for {} images.", transforms.len(), images.len())));
+    }
+
+    aligned_images.clear();
+
+    for i in 0..images.len() {
+        let mut aligned_image: Mat = Mat::default()?;
+        warp_image(&images.get(i)?, &transforms[i], &mut aligned_image)?;
+        aligned_images.push(aligned_image);
+    }
+
+    Ok(())
+}
+
+// apply_transforms, then crop the black borders the warps bring in, see
+// valid_region::crop_to_common_area.
+pub fn apply_transforms_and_crop(images: &VectorOfMat,
+                                 transforms: &Vec<Mat>,
+                                 aligned_images: &mut VectorOfMat) -> Result<Rect, HdrError> {
+    let mut uncropped_images = VectorOfMat::new();
+    apply_transforms(images, transforms, &mut uncropped_images)?;
+
+    let mut masks = VectorOfMat::new();
+    valid_region::create_full_masks(images, &mut masks)?;
+    let mut warped_masks = VectorOfMat::new();
+    apply_transforms(&masks, transforms, &mut warped_masks)?;
+
+    valid_region::crop_to_common_area(&uncropped_images, &warped_masks, aligned_images)
+}
+
+fn motion_type(motion_model: MotionModel) -> i32 {
+    match motion_model {
+        MotionModel::Translation => opencv::video::MOTION_TRANSLATION,
+        MotionModel::Euclidean => opencv::video::MOTION_EUCLIDEAN,
+        MotionModel::Affine => opencv::video::MOTION_AFFINE,
+        MotionModel::Homography => opencv::video::MOTION_HOMOGRAPHY,
+    }
+}
+
+fn identity_warp(motion_model: MotionModel) -> Result<Mat, HdrError> {
+    let rows = if motion_model == MotionModel::Homography { 3 } else { 2 };
+    Ok(Mat::eye(rows, 3, opencv::core::CV_32FC1)?.to_mat()?)
+}
+
+// Apply a transform estimated on a level to the next finer level,
+// whose coordinates are twice as large.
+fn upscale_warp(warp: &mut Mat,
+                motion_model: MotionModel) -> Result<(), HdrError> {
+    *warp.at_2d_mut::<f32>(0, 2)? *= 2.0;
+    *warp.at_2d_mut::<f32>(1, 2)? *= 2.0;
+    if motion_model == MotionModel::Homography {
+        *warp.at_2d_mut::<f32>(2, 0)? *= 0.5;
+        *warp.at_2d_mut::<f32>(2, 1)? *= 0.5;
+    }
+
+    Ok(())
+}
+
+// Grey images with equalized histograms, as CV_32FC1, from the finest
+// level to the coarsest. Equalization maps every exposure to the same
+// tone distribution, which the correlation can match.
+fn compute_normalized_pyramid(src: &Mat,
+                              max_level: u8,
+                              out_pyramid: &mut Vec<Mat>) -> Result<(), HdrError> {
+    let mut grey_image: Mat = Mat::default()?;
+    cvt_rgb_image_to_grey(src, &mut grey_image)?;
+    let levels = pixel_levels(src)?;
+
+    let mut grey_image_8u: Mat = Mat::default()?;
+    grey_image.convert_to(&mut grey_image_8u, opencv::core::CV_8UC1, 255.0 / (levels - 1) as f64, 0.0)?;
+    let mut equalized_image: Mat = Mat::default()?;
+    opencv::imgproc::equalize_hist(&grey_image_8u, &mut equalized_image)?;
+
+    let mut cur_level: Mat = Mat::default()?;
+    equalized_image.convert_to(&mut cur_level, opencv::core::CV_32FC1, 1.0 / 255.0, 0.0)?;
+    for _i in 1..max_level {
+        let mut next_level: Mat = Mat::default()?;
+        opencv::imgproc::pyr_down(&cur_level, &mut next_level, Size::default(), opencv::core::BORDER_DEFAULT)?;
+        out_pyramid.push(cur_level);
+        cur_level = next_level;
+    }
+    out_pyramid.push(cur_level);
+
+    Ok(())
+}
+
+// The transform maps reference coordinates to image coordinates, hence
+// the inverse map flag.
+fn warp_image(src: &Mat,
+              warp: &Mat,
+              dst: &mut Mat) -> Result<(), HdrError> {
+    let size = Size::new(src.cols(), src.rows());
+    let flags = opencv::imgproc::INTER_LINEAR + opencv::imgproc::WARP_INVERSE_MAP;
+    if warp.rows() == 3 {
+        opencv::imgproc::warp_perspective(src, dst, warp, size, flags,
+                                          opencv::core::BORDER_CONSTANT, opencv::core::Scalar_::default())?;
+    } else {
+        opencv::imgproc::warp_affine(src, dst, warp, size, flags,
+                                     opencv::core::BORDER_CONSTANT, opencv::core::Scalar_::default())?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,7 @@
+/* Copyright 2020 Yuchen Wong */
+
+// Alignment of exposure brackets.
+
+pub mod ecc_image_alignment;
+pub mod mtb_image_alignment;
+pub mod valid_region;
@@ -0,0 +1,288 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat, Rect};
+use opencv::prelude::{MatTrait, Vector};
+use opencv::types::VectorOfMat;
+use std::convert::TryInto;
+
+use crate::base::math_utils::{get_translation_matrix};
+use crate::base::opencv_utils::{check_ldr_images, compute_mtb_image, compute_exclusive_image, cvt_rgb_image_to_grey,
+                                 pixel_levels, warp_affine_with_default, resize_image_with_default};
+use crate::core::alignment::valid_region;
+use crate::error::HdrError;
+
+// Noise tolerance around the median, in 8-bit levels, used by Ward.
+pub const DEFAULT_EXCLUSION_THRESHOLD: u8 = 4;
+// Offsets searched around the estimate of the coarser level.
+pub const DEFAULT_SEARCH_RADIUS: i32 = 1;
+
+// Grey levels, normalized to [0, 1], counted as well exposed when
+// choosing the reference automatically.
+const MIN_EXPOSED_VALUE: f32 = 0.05;
+const MAX_EXPOSED_VALUE: f32 = 0.95;
+
+// The image every other image is aligned onto.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reference {
+    // images.len() >> 1, the middle exposure of a sorted bracket.
+    Middle,
+    Index(usize),
+    // The sharpest image among the well exposed ones, see select_reference.
+    Auto,
+}
+
+// Align the images onto the middle one with Ward's median threshold
+// bitmaps, see estimate_transforms and apply_transforms.
+pub fn align(images: &VectorOfMat,
+             aligned_images: &mut VectorOfMat,
+             max_level: u8) 
+    -> Result<(), HdrError> {
+    let mut transforms: Vec<Mat> = Vec::new();
+    estimate_transforms(images, Reference::Middle, max_level, DEFAULT_EXCLUSION_THRESHOLD,
+                        DEFAULT_SEARCH_RADIUS, &mut transforms)?;
+    apply_transforms(images, &transforms, aligned_images)
+}
+
+// Estimate the translation of every image onto the reference, as a 2x3
+// CV_32FC1 affine matrix per image, the identity for the reference.
+// The transforms can be applied to other renditions of the same bracket,
+// e.g. the linear images developed from raw files.
+// Pixels within exclusion_threshold 8-bit levels of the median are ignored
+// as noise, and every level searches offsets up to search_radius around
+// the estimate of the coarser level.
+pub fn estimate_transforms(images: &VectorOfMat,
+                           reference: Reference,
+                           max_level: u8,
+                           exclusion_threshold: u8,
+                           search_radius: i32,
+                           out_transforms: &mut Vec<Mat>)
+    -> Result<(), HdrError> {
+
+    log::trace!("Start MTB Alignment.");
+    out_transforms.clear();
+    check_ldr_images(images)?;
+    if search_radius < 1 {
+        return Err(HdrError::InvalidArgument(
+            format!("MTB search radius should be at least 1, got {}.", search_radius)));
+    }
+
+    let pivot: usize = select_pivot(images, reference)?;
+
+    log::info!("Align pivot is {}.", pivot);
+
+    let mut pivot_image_pyramid_mtb: VectorOfMat = VectorOfMat::new();
+    let mut pivot_image_pyramid_exor: VectorOfMat = VectorOfMat::new();
+    compute_image_pyramid(&images.get(pivot)?, &mut pivot_image_pyramid_mtb, &mut pivot_image_pyramid_exor,
+                          max_level, exclusion_threshold)?;
+
+    let mut move_x: Vec<i32> = Vec::new();
+    let mut move_y: Vec<i32> = Vec::new();
+    for dx in -search_radius..search_radius + 1 {
+        for dy in -search_radius..search_radius + 1 {
+            move_x.push(dx);
+            move_y.push(dy);
+        }
+    }
+    for i in 0..images.len() {
+        let mut offset_x: i32 = 0;
+        let mut offset_y: i32 = 0;
+        if i != pivot {
+            let mut image_pyramid_mtb: VectorOfMat = VectorOfMat::new();
+            let mut image_pyramid_exor: VectorOfMat = VectorOfMat::new();
+            compute_image_pyramid(&images.get(i)?, &mut image_pyramid_mtb, &mut image_pyramid_exor,
+                                  max_level, exclusion_threshold)?;
+            for j in 0..max_level {
+                offset_x = offset_x * 2;
+                offset_y = offset_y * 2;
+
+                let mut best_move: usize = 0;
+                let mut best_similarity: f64 = -1.0;
+                for k in 0..move_x.len() {
+                    let mut translation_matrix: Mat = Mat::default()?;
+                    get_translation_matrix(&mut translation_matrix, offset_x + move_x[k], offset_y + move_y[k])?;
+
+                    let mut cur_mtb: Mat = Mat::default()?;
+                    let mut cur_exor: Mat = Mat::default()?;
+                    warp_affine_with_default(
+                        &image_pyramid_mtb.get((max_level-j-1).try_into().unwrap())?, &mut cur_mtb, &translation_matrix)?;
+                    warp_affine_with_default(
+                        &image_pyramid_exor.get((max_level-j-1).try_into().unwrap())?, &mut cur_exor, &translation_matrix)?;
+
+                    let cur_similarity: f64 = compute_image_similarity(&cur_mtb, &cur_exor, 
+                                                    &pivot_image_pyramid_mtb.get((max_level-j-1).try_into().unwrap())?, 
+                                                    &pivot_image_pyramid_exor.get((max_level-j-1).try_into().unwrap())?)?;
+                    // We tend not to move the image, so we use the moving distance as
+                    // a penalty as this stage.
+                    let penalty = (move_x[k].abs() as f64) + (move_y[k].abs() as f64);
+
+                    if best_similarity < 0.0 || best_similarity > cur_similarity + penalty {
+                        best_similarity = cur_similarity + penalty;
+                        best_move = k;
+                    }
+                }
+
+                offset_x += move_x[best_move];
+                offset_y += move_y[best_move];
+            }
+
+            log::info!("Image {} with offset {}, {}.", i, offset_x, offset_y);
+        }
+
+        let mut translation_matrix: Mat = Mat::default()?;
+        get_translation_matrix(&mut translation_matrix, offset_x, offset_y)?;
+        out_transforms.push(translation_matrix);
+    }
+
+    log::trace!("MTB Alignment finished.");
+    Ok(())
+}
+
+// Translate every image by its transform from estimate_transforms.
+// The images may be of any type, as long as they have the size of the
+// images the transforms were estimated on.
+pub fn apply_transforms(images: &VectorOfMat,
+                        transforms: &Vec<Mat>,
+                        aligned_images: &mut VectorOfMat) -> Result<(), HdrError> {
+    if images.len() != transforms.len() {
+        return Err(HdrError::InvalidArgument(format!(
+            "Got {} transforms for {} images.", transforms.len(), images.len())));
+    }
+
+    aligned_images.clear();
+
+    for i in 0..images.len() {
+        let mut aligned_image: Mat = Mat::default()?;
+        warp_affine_with_default(&images.get(i)?, &mut aligned_image, &transforms[i])?;
+        aligned_images.push(aligned_image);
+    }
+
+    Ok(())
+}
+
+// apply_transforms, then crop the black borders the shifts bring in, see
+// valid_region::crop_to_common_area.
+pub fn apply_transforms_and_crop(images: &VectorOfMat,
+                                 transforms: &Vec<Mat>,
+                                 aligned_images: &mut VectorOfMat) -> Result<Rect, HdrError> {
+    let mut uncropped_images = VectorOfMat::new();
+    apply_transforms(images, transforms, &mut uncropped_images)?;
+
+    let mut masks = VectorOfMat::new();
+    valid_region::create_full_masks(images, &mut masks)?;
+    let mut warped_masks = VectorOfMat::new();
+    apply_transforms(&masks, transforms, &mut warped_masks)?;
+
+    valid_region::crop_to_common_area(&uncropped_images, &warped_masks, aligned_images)
+}
+
+// The index of the image chosen as reference, shared with the ECC aligner
+// so that both align onto the same image.
+pub fn select_pivot(images: &VectorOfMat,
+                    reference: Reference) -> Result<usize, HdrError> {
+    match reference {
+        Reference::Middle => Ok(images.len() >> 1),
+        Reference::Index(index) => {
+            if index >= images.len() {
+                return Err(HdrError::InvalidArgument(format!(
+                    "Reference image {} is out of range of {} images.", index, images.len())));
+            }
+            Ok(index)
+        },
+        Reference::Auto => select_reference(images),
+    }
+}
+
+// Pick the reference of an unsorted or blurry bracket: the image with the
+// most detail, measured as the variance of its laplacian relative to the
+// variance of its grey levels, weighted by its fraction of well exposed
+// pixels so that a dark or clipped frame is not chosen for its noise.
+pub fn select_reference(images: &VectorOfMat) -> Result<usize, HdrError> {
+    check_ldr_images(images)?;
+
+    let mut best_index: usize = 0;
+    let mut best_score: f64 = -1.0;
+    for i in 0..images.len() {
+        let image: Mat = images.get(i)?;
+        let mut grey_image: Mat = Mat::default()?;
+        cvt_rgb_image_to_grey(&image, &mut grey_image)?;
+        let mut float_image: Mat = Mat::default()?;
+        grey_image.convert_to(&mut float_image, opencv::core::CV_32FC1,
+                              1.0 / (pixel_levels(&image)? - 1) as f64, 0.0)?;
+
+        let mut exposed_count: usize = 0;
+        for row in 0..float_image.rows() {
+            for col in 0..float_image.cols() {
+                let value: f32 = *float_image.at_2d::<f32>(row, col)?;
+                if value >= MIN_EXPOSED_VALUE && value <= MAX_EXPOSED_VALUE {
+                    exposed_count += 1;
+                }
+            }
+        }
+        let exposed_ratio = exposed_count as f64 / (float_image.rows() * float_image.cols()) as f64;
+
+        let mut laplacian_image: Mat = Mat::default()?;
+        opencv::imgproc::laplacian(&float_image, &mut laplacian_image, opencv::core::CV_32F, 1, 1.0, 0.0,
+                                   opencv::core::BORDER_DEFAULT)?;
+        let laplacian_variance = compute_variance(&laplacian_image)?;
+        let grey_variance = compute_variance(&float_image)?;
+
+        let score = if grey_variance > 0.0 { laplacian_variance / grey_variance * exposed_ratio } else { 0.0 };
+        log::info!("Image {} with reference score {}, well exposed ratio {}.", i, score, exposed_ratio);
+        if score > best_score {
+            best_score = score;
+            best_index = i;
+        }
+    }
+
+    Ok(best_index)
+}
+
+fn compute_variance(src: &Mat) -> Result<f64, HdrError> {
+    let mut mean: Mat = Mat::default()?;
+    let mut stddev: Mat = Mat::default()?;
+    opencv::core::mean_std_dev(src, &mut mean, &mut stddev, &opencv::core::no_array()?)?;
+    let stddev: f64 = *stddev.at_2d::<f64>(0, 0)?;
+    Ok(stddev * stddev)
+}
+
+fn compute_image_pyramid(src: &Mat,
+                         out_mtb_images: &mut VectorOfMat,
+                         out_exclusive_images: &mut VectorOfMat,
+                         max_level: u8,
+                         exclusion_threshold: u8)
+    -> Result<(), HdrError> {
+        let mut src_clone: Mat = src.clone()?;
+        let mut scale: f64 = 1.0;
+        for _i in 0..max_level {
+            let mut mtb_image: Mat = Mat::default()?;
+            let mut exclusive_image: Mat = Mat::default()?;
+            log::info!("Resizing with scale {}.", scale);
+            compute_mtb_image(&src_clone, &mut mtb_image)?;
+            compute_exclusive_image(&src_clone, &mut exclusive_image, exclusion_threshold)?;
+
+            out_mtb_images.push(mtb_image);
+            out_exclusive_images.push(exclusive_image);
+
+            scale *= 0.5;
+            resize_image_with_default(src, &mut src_clone, scale, scale)?;
+        }
+
+        Ok(())
+}
+
+fn compute_image_similarity(a_mtb: &Mat,
+                            a_exor: &Mat,
+                            b_mtb: &Mat,
+                            b_exor: &Mat)
+    -> Result<f64, HdrError> {
+    let mut image_and = Mat::default()?;
+    let mut image_xor = Mat::default()?;
+    opencv::core::bitwise_xor(a_mtb, b_mtb, &mut image_xor, &opencv::core::no_array()?)?;
+    opencv::core::bitwise_and(&image_xor, b_exor, &mut image_and, &opencv::core::no_array()?)?;
+    opencv::core::bitwise_and(&image_and, a_exor, &mut image_xor, &opencv::core::no_array()?)?;
+    let sum: f64 = opencv::core::sum_elems(&image_xor)?[0];
+
+    log::info!("Computed image similarity: {}", sum);
+
+    Ok(sum / 255.0)
+}
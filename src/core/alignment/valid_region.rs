@@ -0,0 +1,114 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat, Rect, Scalar};
+use opencv::prelude::{MatTrait, Vector};
+use opencv::types::VectorOfMat;
+
+use crate::error::HdrError;
+
+// Aligned images are shifted in with black borders, which would enter the
+// CRF samples and darken the edges of the radiance map. This file finds
+// the area every aligned image covers and crops the bracket to it.
+
+// Full masks of the size of the images, to be warped by the aligner
+// like the images themselves.
+pub fn create_full_masks(images: &VectorOfMat,
+                         out_masks: &mut VectorOfMat) -> Result<(), HdrError> {
+    out_masks.clear();
+    for i in 0..images.len() {
+        let image: Mat = images.get(i)?;
+        out_masks.push(Mat::new_rows_cols_with_default(
+            image.rows(), image.cols(), opencv::core::CV_8UC1, Scalar::all(255.0))?);
+    }
+
+    Ok(())
+}
+
+// The CV_8UC1 mask of the pixels valid in every warped mask. Pixels
+// interpolated with the border are partially valid and dropped.
+pub fn compute_common_mask(warped_masks: &VectorOfMat,
+                           out_mask: &mut Mat) -> Result<(), HdrError> {
+    if warped_masks.len() == 0 {
+        return Err(HdrError::InvalidArgument(String::from("No masks to intersect.")));
+    }
+
+    *out_mask = warped_masks.get(0)?.clone()?;
+    for i in 1..warped_masks.len() {
+        let mut common_mask: Mat = Mat::default()?;
+        opencv::core::min(&*out_mask, &warped_masks.get(i)?, &mut common_mask)?;
+        *out_mask = common_mask;
+    }
+
+    Ok(())
+}
+
+// The largest axis aligned rectangle of fully valid pixels in the mask,
+// found row by row as the largest rectangle under the histogram of valid
+// pixel runs above each row.
+pub fn find_valid_rect(mask: &Mat) -> Result<Rect, HdrError> {
+    let rows = mask.rows() as usize;
+    let cols = mask.cols() as usize;
+
+    let mut heights: Vec<usize> = vec![0; cols];
+    let mut best_area: usize = 0;
+    let mut best_rect: Rect = Rect::default();
+    for i in 0..rows {
+        for j in 0..cols {
+            if *mask.at_2d::<u8>(i as i32, j as i32)? == 255 {
+                heights[j] += 1;
+            } else {
+                heights[j] = 0;
+            }
+        }
+
+        let mut stack: Vec<usize> = Vec::new();
+        for j in 0..cols + 1 {
+            let height = if j < cols { heights[j] } else { 0 };
+            while let Some(&top) = stack.last() {
+                if heights[top] <= height {
+                    break;
+                }
+                stack.pop();
+                let left = stack.last().map_or(0, |&k| k + 1);
+                let area = heights[top] * (j - left);
+                if area > best_area {
+                    best_area = area;
+                    best_rect = Rect::new(left as i32, (i + 1 - heights[top]) as i32,
+                                          (j - left) as i32, heights[top] as i32);
+                }
+            }
+            stack.push(j);
+        }
+    }
+
+    if best_area == 0 {
+        return Err(HdrError::InvalidArgument(String::from("Aligned images have no common valid area.")));
+    }
+
+    log::info!("Common valid area at {}, {} of {}x{}.", best_rect.x, best_rect.y, best_rect.width, best_rect.height);
+    Ok(best_rect)
+}
+
+// Crop the warped images to the largest rectangle valid in every warped
+// mask, and return it, in the coordinates of the reference.
+pub fn crop_to_common_area(warped_images: &VectorOfMat,
+                           warped_masks: &VectorOfMat,
+                           out_images: &mut VectorOfMat) -> Result<Rect, HdrError> {
+    let mut common_mask: Mat = Mat::default()?;
+    compute_common_mask(warped_masks, &mut common_mask)?;
+    let rect = find_valid_rect(&common_mask)?;
+
+    crop_images(warped_images, rect, out_images)?;
+    Ok(rect)
+}
+
+pub fn crop_images(images: &VectorOfMat,
+                   rect: Rect,
+                   out_images: &mut VectorOfMat) -> Result<(), HdrError> {
+    out_images.clear();
+    for i in 0..images.len() {
+        out_images.push(Mat::roi(&images.get(i)?, rect)?.clone()?);
+    }
+
+    Ok(())
+}
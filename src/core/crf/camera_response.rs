@@ -0,0 +1,207 @@
+/* Copyright 2020 Yuchen Wong */
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use crate::error::HdrError;
+
+// The response curve of a camera recovered by a CRF solver.
+// For each channel (in BGR order) it stores g(z) = ln(E * dt),
+// the log exposure producing the pixel value z.
+#[derive(Clone, Debug)]
+pub struct CameraResponse {
+    curves: [Vec<f32>; 3],
+}
+
+impl CameraResponse {
+    pub fn new(curves: [Vec<f32>; 3]) -> Result<CameraResponse, HdrError> {
+        let levels = curves[0].len();
+        if levels < 2 {
+            return Err(HdrError::InvalidArgument(
+                format!("A response curve needs at least 2 levels, got {}.", levels)));
+        }
+        for c in 1..3 {
+            if curves[c].len() != levels {
+                return Err(HdrError::InvalidArgument(
+                    format!("Channel {} has {} levels while channel 0 has {}.", c, curves[c].len(), levels)));
+            }
+        }
+
+        Ok(CameraResponse { curves: curves })
+    }
+
+    // A linear response g(z) = ln(z / (levels - 1)) for inputs which are
+    // already linear, e.g. images developed from RAW files.
+    pub fn linear(levels: usize) -> Result<CameraResponse, HdrError> {
+        let max_val = levels.saturating_sub(1) as f32;
+        let mut curve: Vec<f32> = Vec::with_capacity(levels);
+        for z in 0..levels {
+            // Pixel value 0 has a zero weight in the merge, we only
+            // keep its log exposure finite.
+            curve.push(((z as f32).max(0.5) / max_val).ln());
+        }
+
+        CameraResponse::new([curve.clone(), curve.clone(), curve])
+    }
+
+    // Linearly interpolate the curve to another number of levels, e.g. to
+    // apply a curve solved on 256 bins to 16-bit images.
+    pub fn resample(&self, levels: usize) -> Result<CameraResponse, HdrError> {
+        if levels < 2 {
+            return Err(HdrError::InvalidArgument(
+                format!("A response curve needs at least 2 levels, got {}.", levels)));
+        }
+
+        let src_max = (self.levels() - 1) as f64;
+        let dst_max = (levels - 1) as f64;
+        let mut curves: [Vec<f32>; 3] = [Vec::new(), Vec::new(), Vec::new()];
+        for c in 0..3 {
+            let src_curve = &self.curves[c];
+            let mut curve: Vec<f32> = Vec::with_capacity(levels);
+            for z in 0..levels {
+                let x = z as f64 * src_max / dst_max;
+                let i = (x.floor() as usize).min(self.levels() - 1);
+                let i1 = (i + 1).min(self.levels() - 1);
+                let t = (x - i as f64) as f32;
+                curve.push(src_curve[i] * (1.0 - t) + src_curve[i1] * t);
+            }
+            curves[c] = curve;
+        }
+
+        CameraResponse::new(curves)
+    }
+
+    // Number of pixel values covered by the curve, e.g. 256 for 8-bit images.
+    pub fn levels(&self) -> usize {
+        self.curves[0].len()
+    }
+
+    pub fn curve(&self, channel: usize) -> &[f32] {
+        &self.curves[channel]
+    }
+
+    // The log exposure g(z) of a pixel value z in a channel.
+    pub fn log_exposure(&self, channel: usize, z: usize) -> f32 {
+        self.curves[channel][z]
+    }
+
+    // Save the curve as a csv file, one line per pixel value:
+    // z, g_b(z), g_g(z), g_r(z)
+    pub fn save(&self, path: &str) -> Result<(), HdrError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "# z,b,g,r")?;
+        for z in 0..self.levels() {
+            writeln!(writer, "{},{},{},{}", z,
+                     self.curves[0][z], self.curves[1][z], self.curves[2][z])?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    // Load a curve saved by CameraResponse::save.
+    pub fn load(path: &str) -> Result<CameraResponse, HdrError> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut curves: [Vec<f32>; 3] = [Vec::new(), Vec::new(), Vec::new()];
+        for (line_num, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            if fields.len() != 4 {
+                return Err(HdrError::InvalidFormat(
+                    format!("{}:{}: expected 4 fields, got {}.", path, line_num + 1, fields.len())));
+            }
+
+            let z = fields[0].parse::<usize>().map_err(|_| HdrError::InvalidFormat(
+                format!("{}:{}: invalid pixel value {}.", path, line_num + 1, fields[0])))?;
+            if z != curves[0].len() {
+                return Err(HdrError::InvalidFormat(
+                    format!("{}:{}: expected pixel value {}, got {}.", path, line_num + 1, curves[0].len(), z)));
+            }
+
+            for c in 0..3 {
+                let g = fields[c + 1].parse::<f32>().map_err(|_| HdrError::InvalidFormat(
+                    format!("{}:{}: invalid log exposure {}.", path, line_num + 1, fields[c + 1])))?;
+                curves[c].push(g);
+            }
+        }
+
+        CameraResponse::new(curves)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::test_utils::TempFile;
+
+    fn load_str(name: &str,
+                content: &str) -> Result<CameraResponse, HdrError> {
+        let file = TempFile::with_content(name, content.as_bytes());
+        CameraResponse::load(file.path())
+    }
+
+    #[test]
+    fn rejects_invalid_curves() {
+        assert!(CameraResponse::new([vec![0.0], vec![0.0], vec![0.0]]).is_err());
+        assert!(CameraResponse::new([vec![0.0, 1.0], vec![0.0, 1.0], vec![0.0]]).is_err());
+    }
+
+    #[test]
+    fn resample_keeps_end_points() {
+        let response = CameraResponse::linear(256).unwrap();
+        assert!(response.resample(1).is_err());
+        let resampled = response.resample(65536).unwrap();
+        assert_eq!(resampled.levels(), 65536);
+        for c in 0..3 {
+            assert_eq!(resampled.log_exposure(c, 0), response.log_exposure(c, 0));
+            assert_eq!(resampled.log_exposure(c, 65535), response.log_exposure(c, 255));
+            assert!(resampled.curve(c).windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+
+        let back = resampled.resample(256).unwrap();
+        for z in 0..256 {
+            assert!((back.log_exposure(1, z) - response.log_exposure(1, z)).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let curves: [Vec<f32>; 3] = [
+            (0..256).map(|z| (z as f32 * 0.01) - 2.0).collect(),
+            (0..256).map(|z| (z as f32 * 0.02) - 3.0).collect(),
+            (0..256).map(|z| (z as f32 / 255.0).max(1e-3).ln()).collect(),
+        ];
+        let response = CameraResponse::new(curves).unwrap();
+        let file = TempFile::new("round_trip.csv");
+        response.save(file.path()).unwrap();
+
+        let loaded = CameraResponse::load(file.path()).unwrap();
+        assert_eq!(loaded.levels(), 256);
+        for c in 0..3 {
+            assert_eq!(loaded.curve(c), response.curve(c));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_csv() {
+        assert!(load_str("valid.csv", "# z,b,g,r\n0,-1,-1,-1\n\n1, 0, 0, 0\n").is_ok());
+
+        for (name, content) in [("fields.csv", "0,-1,-1\n1,0,0\n"),
+                                ("order.csv", "0,-1,-1,-1\n2,0,0,0\n"),
+                                ("pixel.csv", "0,-1,-1,-1\nx,0,0,0\n"),
+                                ("value.csv", "0,-1,-1,-1\n1,0,nope,0\n"),
+                                ("short.csv", "# z,b,g,r\n0,-1,-1,-1\n")].iter() {
+            match load_str(name, content) {
+                Err(HdrError::InvalidFormat(_)) | Err(HdrError::InvalidArgument(_)) => {},
+                other => panic!("{}: expected an error, got {:?}", name, other),
+            }
+        }
+        let missing = TempFile::new("missing.csv");
+        assert!(CameraResponse::load(missing.path()).is_err());
+    }
+}
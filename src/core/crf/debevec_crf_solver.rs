@@ -1,74 +1,81 @@
 /* Copyright 2020 Yuchen Wong*/
 
-use opencv::core::{Mat, MatExprTrait, Vec3b, Vec3f};
+use opencv::core::{Mat, MatExprTrait};
 use opencv::prelude::*;
 use opencv::types::VectorOfMat;
-use std::error::Error;
 use std::time::SystemTime;
 
-#[path = "../base/math_utils.rs"] mod math_utils;
-#[path = "../base/opencv_utils.rs"] mod opencv_utils;
-
-use math_utils::{hat};
-use opencv_utils::matmul;
+use crate::base::math_utils;
+use crate::base::math_utils::{bin_pixel_value, generate_hat_weights};
+use crate::base::opencv_utils::{check_exposures, check_ldr_images, get_channel_value, matmul};
+use crate::core::crf::camera_response::CameraResponse;
+use crate::core::crf::radiance_map_merger;
+use crate::error::HdrError;
 
 pub fn solve(images: &VectorOfMat,
              shutter_speeds: &Vec<f32>,
              sample_num: i32,
              lambda: f32,
-             out_hdri: &mut Mat) -> Result<(), Box<dyn Error>> {
+             out_hdri: &mut Mat) -> Result<CameraResponse, HdrError> {
 
     log::trace!("Debevec crf_solver started.");
 
     let start = SystemTime::now();
 
-    // generate weights with a hat function.
-    let mut weights: [f32; 256] = [0.0; 256];
-    for i in 0..256 {
-        weights[i] = hat(i as f32, 0.0, 255.0);
-    }
+    let response: CameraResponse = solve_response(images, shutter_speeds, sample_num, lambda)?;
+    radiance_map_merger::merge(images, shutter_speeds, &response, out_hdri)?;
 
-    let rows: i32 = images.get(0)?.rows();
-    let cols: i32 = images.get(0)?.cols();
+    let duration = start.elapsed().map(|d| d.as_secs()).unwrap_or(0);
+    log::trace!("Debevec crf_solver ended: {} secs.", duration);
+    Ok(response)
+}
 
-    // shape the output radiance map.
-    unsafe {
-        out_hdri.create_rows_cols(rows, cols, opencv::core::CV_32FC3)?;
+// Only recover the camera response curve of a bracket, without
+// constructing its radiance map.
+// 16-bit brackets are solved on 256 bins, as a system with 65536
+// unknowns is out of reach of the SVD, and the curve is interpolated
+// back to 65536 levels.
+pub fn solve_response(images: &VectorOfMat,
+                      shutter_speeds: &Vec<f32>,
+                      sample_num: i32,
+                      lambda: f32) -> Result<CameraResponse, HdrError> {
+    let levels = check_ldr_images(images)?;
+    check_exposures(images, shutter_speeds)?;
+    // histogram_sampling distributes sample_num / 16 samples per weight unit.
+    if sample_num < 16 {
+        return Err(HdrError::InvalidArgument(
+            format!("Debevec solver needs at least 16 samples, got {}.", sample_num)));
     }
 
-//    let mut samples_x: Vec<i32> = Vec::with_capacity(sample_num as usize);
-//    let mut samples_y: Vec<i32> = Vec::with_capacity(sample_num as usize);
-//    for _i in 0..sample_num {
-//        samples_x.push(math_utils::gen_random_integer(0, cols));
-//        samples_y.push(math_utils::gen_random_integer(0, rows));
-//    }
+    let weights: Vec<f32> = generate_hat_weights(256);
 
-    // Solve the radiance map for each channel
+    // Solve the response curve for each channel
+    let mut curves: [Vec<f32>; 3] = [Vec::new(), Vec::new(), Vec::new()];
     for c in 0..3 {
-        solve_internal(images, shutter_speeds, lambda, &weights, sample_num, c as usize, out_hdri)?;
+        curves[c] = solve_internal(images, shutter_speeds, lambda, &weights, sample_num, levels, c as usize)?;
     }
 
-    let duration = start.elapsed()?.as_secs();
-    log::trace!("Debevec crf_solver ended: {} secs.", duration);
-    Ok(())
+    let response = CameraResponse::new(curves)?;
+    if levels != 256 {
+        return response.resample(levels);
+    }
+    Ok(response)
 }
 
 fn solve_internal(images: &VectorOfMat,
                   shutter_speeds: &Vec<f32>,
                   lambda: f32,
-                  weights: &[f32; 256],
+                  weights: &[f32],
                   sample_number: i32,
-                  channel: usize,
-                  out_hdri: &mut Mat) -> Result<(), Box<dyn Error>> {
+                  levels: usize,
+                  channel: usize) -> Result<Vec<f32>, HdrError> {
 
     log::trace!("Solving Debevec CRF for channel {}.", channel);
 
     let mut samples_x: Vec<i32> = Vec::default();
     let mut samples_y: Vec<i32> = Vec::default();
-    histogram_sampling(images, channel, sample_number, &mut samples_x, &mut samples_y)?;
+    histogram_sampling(images, channel, sample_number, levels, &mut samples_x, &mut samples_y)?;
 
-    let rows = images.get(0)?.rows();
-    let cols = images.get(0)?.cols();
     let sample_num: usize = samples_x.len();
     let image_num: i32 = images.len() as i32;
 
@@ -83,21 +90,22 @@ fn solve_internal(images: &VectorOfMat,
         let cur_image: Mat = images.get(p as usize)?;
         for i in 0..sample_num {
             // log::trace!("{} {}", samples_y[i], samples_x[i]);
-            let z: Vec3b = *cur_image.at_2d::<Vec3b>(samples_x[i], samples_y[i])?;
-            *a.at_2d_mut::<f32>(l, z[channel] as i32).unwrap() = 1.0 * weights[z[channel] as usize];
-            *a.at_2d_mut::<f32>(l, (256 + i) as i32).unwrap() = -1.0 * weights[z[channel] as usize];
-            *b.at_2d_mut::<f32>(l, 0).unwrap() = shutter_speeds[p as usize].ln() * weights[z[channel] as usize];
+            let z: usize = bin_pixel_value(
+                get_channel_value(&cur_image, samples_x[i], samples_y[i], channel, levels)?, levels, 256);
+            *a.at_2d_mut::<f32>(l, z as i32)? = 1.0 * weights[z];
+            *a.at_2d_mut::<f32>(l, (256 + i) as i32)? = -1.0 * weights[z];
+            *b.at_2d_mut::<f32>(l, 0)? = shutter_speeds[p as usize].ln() * weights[z];
             l += 1;
         }
     }
 
-    *a.at_2d_mut::<f32>(l, 127).unwrap() = 1.0;
+    *a.at_2d_mut::<f32>(l, 127)? = 1.0;
     l += 1;
 
     for i in 1..255 {
-        *a.at_2d_mut::<f32>(l, i-1).unwrap() = lambda * weights[i as usize];
-        *a.at_2d_mut::<f32>(l, i).unwrap() = -2.0 * lambda * weights[i as usize];
-        *a.at_2d_mut::<f32>(l, i+1).unwrap() = lambda * weights[i as usize];
+        *a.at_2d_mut::<f32>(l, i-1)? = lambda * weights[i as usize];
+        *a.at_2d_mut::<f32>(l, i)? = -2.0 * lambda * weights[i as usize];
+        *a.at_2d_mut::<f32>(l, i+1)? = lambda * weights[i as usize];
         l += 1;
     }
 
@@ -111,39 +119,31 @@ fn solve_internal(images: &VectorOfMat,
     let mut x: Mat = Mat::default()?;
     matmul(&a_inv, &b, opencv::core::CV_32FC1, &mut x)?;
 
-    let mut g: [f32; 256] = [0.0; 256];
+    let mut g: Vec<f32> = Vec::with_capacity(256);
     for i in 0..256 {
-        g[i as usize] = *x.at_2d::<f32>(i, 0).unwrap();
-    }
-
-    log::trace!("Starting recovering for channel {}.", channel);
-    for row in 0..rows {
-        for col in 0..cols {
-            let mut sum_weight: f32 = 0.0;
-            let mut sum_radiance: f32 = 0.0;
-            for p in 0..image_num {
-                let z: Vec3b = *images.get(p as usize)?.at_2d::<Vec3b>(row, col).unwrap();
-                sum_weight += weights[z[channel] as usize];
-                sum_radiance += weights[z[channel] as usize] * (g[z[channel] as usize] - shutter_speeds[p as usize].ln());
-            }
-            out_hdri.at_2d_mut::<Vec3f>(row, col).unwrap()[channel] = (sum_radiance / (sum_weight+0.0001)).exp();
-        }
+        g.push(*x.at_2d::<f32>(i, 0)?);
     }
 
     log::trace!("Finishing solving Debevec CRF for channel {}.", channel);
-    Ok(())
+    Ok(g)
 }
 
 fn histogram_sampling(images: &VectorOfMat,
                       channel: usize,
                       sample_num: i32,
+                      levels: usize,
                       out_sample_x: &mut Vec<i32>,
-                      out_sample_y: &mut Vec<i32>) -> Result<(), Box<dyn Error>> {
+                      out_sample_y: &mut Vec<i32>) -> Result<(), HdrError> {
     let bin_num = 8;
     let image_num: usize = images.len();
     let rows = images.get(0)?.rows();
     let cols = images.get(0)?.cols();
 
+    let mut image_list: Vec<Mat> = Vec::with_capacity(image_num);
+    for k in 0..image_num {
+        image_list.push(images.get(k)?);
+    }
+
     let mut bin_x: Vec<Vec<i32>> = Vec::with_capacity(bin_num as usize);
     let mut bin_y: Vec<Vec<i32>> = Vec::with_capacity(bin_num as usize);
     let bin_size: i32 = 256 / bin_num;
@@ -156,7 +156,8 @@ fn histogram_sampling(images: &VectorOfMat,
         for j in 0..cols {
             let mut sum_value: i32 = 0;
             for k in 0..image_num {
-                sum_value += images.get(k)?.at_2d::<Vec3b>(i, j).unwrap()[channel] as i32;
+                let z = get_channel_value(&image_list[k], i, j, channel, levels)?;
+                sum_value += bin_pixel_value(z, levels, 256) as i32;
             }
             sum_value /= image_num as i32;
             bin_x[(sum_value / bin_size) as usize].push(i);
@@ -169,6 +170,9 @@ fn histogram_sampling(images: &VectorOfMat,
     let samples_per_bin: i32 = sample_num / 16;
     for i in 0..bin_num {
         let cur_bin_size = bin_x[i as usize].len() as i32;
+        if cur_bin_size == 0 {
+            continue;
+        }
         let cur_samples = weights[i as usize] * samples_per_bin;
         for _j in 0..cur_samples {
             let cur_index = math_utils::gen_random_integer(0, cur_bin_size);
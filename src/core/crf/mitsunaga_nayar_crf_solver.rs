@@ -0,0 +1,239 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat, MatExprTrait};
+use opencv::prelude::{MatTrait, Vector};
+use opencv::types::VectorOfMat;
+use std::time::SystemTime;
+
+use crate::base::math_utils;
+use crate::base::opencv_utils::{check_exposures, check_ldr_images, get_channel_value};
+use crate::core::crf::camera_response::CameraResponse;
+use crate::core::crf::radiance_map_merger;
+use crate::error::HdrError;
+
+// Normalized pixel values outside of this range are too noisy or
+// saturated to constrain the response polynomial.
+const MIN_VALID_PIXEL: f64 = 5.0 / 255.0;
+const MAX_VALID_PIXEL: f64 = 250.0 / 255.0;
+
+// Mitsunaga and Nayar's radiometric self calibration.
+// The response is modeled as a polynomial f(M) = sum(c_n * M^n) of the
+// normalized pixel value M, with f(1) = 1, and the exposure ratios
+// R_q = t_q / t_q+1 between consecutive images are refined jointly
+// from rough initial shutter speeds, so brackets without reliable
+// exposure times can still be merged.
+//
+// out_exposure_ratios receives the refined R_q for every pair of
+// consecutive images in the input order. The radiance map is merged
+// with exposure times derived from them, keeping the first image's
+// shutter speed.
+pub fn solve(images: &VectorOfMat,
+             initial_shutter_speeds: &Vec<f32>,
+             degree: usize,
+             sample_num: i32,
+             max_iterations: i32,
+             threshold: f32,
+             out_hdri: &mut Mat,
+             out_exposure_ratios: &mut Vec<f32>) -> Result<CameraResponse, HdrError> {
+
+    log::trace!("Mitsunaga-Nayar crf_solver started.");
+
+    let levels = check_ldr_images(images)?;
+    check_exposures(images, initial_shutter_speeds)?;
+    if images.len() < 2 {
+        return Err(HdrError::InvalidArgument(
+            format!("Mitsunaga-Nayar solver needs at least 2 images, got {}.", images.len())));
+    }
+    if degree < 1 {
+        return Err(HdrError::InvalidArgument(
+            format!("Polynomial degree must be at least 1, got {}.", degree)));
+    }
+    if max_iterations < 1 {
+        return Err(HdrError::InvalidArgument(
+            format!("Mitsunaga-Nayar solver needs at least 1 iteration, got {}.", max_iterations)));
+    }
+
+    let start = SystemTime::now();
+
+    let image_num = images.len();
+    let mut ratios: Vec<f64> = Vec::with_capacity(image_num - 1);
+    for q in 0..image_num-1 {
+        ratios.push((initial_shutter_speeds[q] / initial_shutter_speeds[q+1]) as f64);
+    }
+
+    let mut samples: Vec<Vec<(usize, f64, f64)>> = Vec::with_capacity(3);
+    for c in 0..3 {
+        samples.push(collect_samples(images, c, sample_num, levels)?);
+        if samples[c].len() < degree + 1 {
+            return Err(HdrError::InvalidArgument(
+                format!("Only {} valid samples in channel {} for a polynomial of degree {}.",
+                        samples[c].len(), c, degree)));
+        }
+    }
+
+    let mut coefficients: Vec<Vec<f64>> = Vec::with_capacity(3);
+    for c in 0..3 {
+        coefficients.push(fit_polynomial(&samples[c], &ratios, degree)?);
+    }
+
+    for iteration in 0..max_iterations {
+        update_ratios(&samples, &coefficients, &mut ratios);
+
+        let mut diff: f64 = 0.0;
+        for c in 0..3 {
+            let new_coefficients = fit_polynomial(&samples[c], &ratios, degree)?;
+            for z in 0..256 {
+                let m = z as f64 / 255.0;
+                let cur_diff = (evaluate_polynomial(&new_coefficients, m) -
+                                evaluate_polynomial(&coefficients[c], m)).abs();
+                if cur_diff > diff {
+                    diff = cur_diff;
+                }
+            }
+            coefficients[c] = new_coefficients;
+        }
+
+        log::trace!("Mitsunaga-Nayar iteration {}: {}.", iteration, diff);
+        if diff < threshold as f64 {
+            break;
+        }
+    }
+
+    out_exposure_ratios.clear();
+    for q in 0..image_num-1 {
+        log::info!("Exposure ratio between image {} and {}: {}.", q, q+1, ratios[q]);
+        out_exposure_ratios.push(ratios[q] as f32);
+    }
+
+    // The response stores log exposures as Debevec's g(z).
+    let mut curves: [Vec<f32>; 3] = [Vec::new(), Vec::new(), Vec::new()];
+    for c in 0..3 {
+        let mut g: Vec<f32> = Vec::with_capacity(levels);
+        for z in 0..levels {
+            let f = evaluate_polynomial(&coefficients[c], z as f64 / (levels - 1) as f64);
+            g.push(f.max(math_utils::EPSILON).ln() as f32);
+        }
+        curves[c] = g;
+    }
+    let response = CameraResponse::new(curves)?;
+
+    let mut shutter_speeds: Vec<f32> = Vec::with_capacity(image_num);
+    shutter_speeds.push(initial_shutter_speeds[0]);
+    for q in 0..image_num-1 {
+        let next_shutter_speed = shutter_speeds[q] / out_exposure_ratios[q];
+        shutter_speeds.push(next_shutter_speed);
+    }
+    radiance_map_merger::merge(images, &shutter_speeds, &response, out_hdri)?;
+
+    let duration = start.elapsed().map(|d| d.as_secs()).unwrap_or(0);
+    log::trace!("Mitsunaga-Nayar crf_solver ended: {} secs.", duration);
+    Ok(response)
+}
+
+// Minimize sum((f(M_q) - R_q * f(M_q+1))^2) over the coefficients
+// with c_N = 1 - sum(c_n), which is a linear least squares problem
+// on c_0 .. c_N-1.
+fn fit_polynomial(samples: &Vec<(usize, f64, f64)>,
+                  ratios: &Vec<f64>,
+                  degree: usize) -> Result<Vec<f64>, HdrError> {
+    let mut a: Mat = Mat::zeros(degree as i32, degree as i32, opencv::core::CV_64FC1)?.to_mat()?;
+    let mut b: Mat = Mat::zeros(degree as i32, 1, opencv::core::CV_64FC1)?.to_mat()?;
+
+    let mut d: Vec<f64> = vec![0.0; degree + 1];
+    for (q, m_q, m_q1) in samples {
+        for n in 0..degree+1 {
+            d[n] = m_q.powi(n as i32) - ratios[*q] * m_q1.powi(n as i32);
+        }
+        for i in 0..degree {
+            let a_i = d[i] - d[degree];
+            for j in 0..degree {
+                *a.at_2d_mut::<f64>(i as i32, j as i32)? += a_i * (d[j] - d[degree]);
+            }
+            *b.at_2d_mut::<f64>(i as i32, 0)? -= a_i * d[degree];
+        }
+    }
+
+    let mut x: Mat = Mat::default()?;
+    opencv::core::solve(&a, &b, &mut x, opencv::core::DECOMP_SVD)?;
+
+    let mut coefficients: Vec<f64> = Vec::with_capacity(degree + 1);
+    let mut sum: f64 = 0.0;
+    for n in 0..degree {
+        let c_n = *x.at_2d::<f64>(n as i32, 0)?;
+        sum += c_n;
+        coefficients.push(c_n);
+    }
+    coefficients.push(1.0 - sum);
+
+    Ok(coefficients)
+}
+
+// R_q is the mean of f(M_q) / f(M_q+1) over the samples of all channels.
+fn update_ratios(samples: &Vec<Vec<(usize, f64, f64)>>,
+                 coefficients: &Vec<Vec<f64>>,
+                 ratios: &mut Vec<f64>) {
+    let mut sum_ratio: Vec<f64> = vec![0.0; ratios.len()];
+    let mut count: Vec<usize> = vec![0; ratios.len()];
+    for c in 0..samples.len() {
+        for (q, m_q, m_q1) in &samples[c] {
+            let f_q1 = evaluate_polynomial(&coefficients[c], *m_q1);
+            if f_q1 < math_utils::EPSILON {
+                continue;
+            }
+            sum_ratio[*q] += evaluate_polynomial(&coefficients[c], *m_q) / f_q1;
+            count[*q] += 1;
+        }
+    }
+
+    for q in 0..ratios.len() {
+        if count[q] > 0 {
+            ratios[q] = sum_ratio[q] / count[q] as f64;
+        }
+    }
+}
+
+fn evaluate_polynomial(coefficients: &Vec<f64>,
+                       m: f64) -> f64 {
+    let mut res: f64 = 0.0;
+    for n in (0..coefficients.len()).rev() {
+        res = res * m + coefficients[n];
+    }
+
+    res
+}
+
+// Sample pixels on a regular grid. Each sample is (q, M_q, M_q+1), the
+// normalized values of one pixel in image q and q+1, kept only if both
+// are well exposed.
+fn collect_samples(images: &VectorOfMat,
+                   channel: usize,
+                   sample_num: i32,
+                   levels: usize) -> Result<Vec<(usize, f64, f64)>, HdrError> {
+    let max_value = (levels - 1) as f64;
+    let rows = images.get(0)?.rows();
+    let cols = images.get(0)?.cols();
+    let step = ((((rows * cols) as f64) / (sample_num.max(1) as f64)).sqrt() as i32).max(1);
+
+    let mut samples: Vec<(usize, f64, f64)> = Vec::new();
+    for q in 0..images.len()-1 {
+        let cur_image: Mat = images.get(q)?;
+        let next_image: Mat = images.get(q+1)?;
+        let mut row = step / 2;
+        while row < rows {
+            let mut col = step / 2;
+            while col < cols {
+                let m_q = get_channel_value(&cur_image, row, col, channel, levels)? as f64 / max_value;
+                let m_q1 = get_channel_value(&next_image, row, col, channel, levels)? as f64 / max_value;
+                if m_q >= MIN_VALID_PIXEL && m_q <= MAX_VALID_PIXEL &&
+                    m_q1 >= MIN_VALID_PIXEL && m_q1 <= MAX_VALID_PIXEL {
+                    samples.push((q, m_q, m_q1));
+                }
+                col += step;
+            }
+            row += step;
+        }
+    }
+
+    log::trace!("Collected {} samples for channel {}.", samples.len(), channel);
+    Ok(samples)
+}
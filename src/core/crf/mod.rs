@@ -0,0 +1,9 @@
+/* Copyright 2020 Yuchen Wong */
+
+// Camera response recovery and radiance map construction.
+
+pub mod camera_response;
+pub mod debevec_crf_solver;
+pub mod mitsunaga_nayar_crf_solver;
+pub mod radiance_map_merger;
+pub mod robertson_crf_solver;
@@ -0,0 +1,290 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat, MatExprTrait, Scalar_, Vec3f};
+use opencv::prelude::{MatTrait, Vector};
+use opencv::types::VectorOfMat;
+
+use crate::base::math_utils::{generate_hat_weights};
+use crate::base::opencv_utils::{check_exposures, check_images, check_ldr_images, get_channel_value};
+use crate::core::crf::camera_response::CameraResponse;
+use crate::error::HdrError;
+
+// Merge an aligned bracket into a radiance map with a known camera response,
+// so that brackets from a calibrated camera do not need to solve the CRF again.
+// 8-bit brackets need a response with 256 levels and 16-bit ones 65536 levels.
+pub fn merge(images: &VectorOfMat,
+             shutter_speeds: &Vec<f32>,
+             response: &CameraResponse,
+             out_hdri: &mut Mat) -> Result<(), HdrError> {
+    log::trace!("Radiance map merging started.");
+
+    let levels = check_merge_inputs(images, shutter_speeds, response)?;
+
+    // generate weights with a hat function.
+    let weights: Vec<f32> = generate_hat_weights(levels);
+
+    let rows: i32 = images.get(0)?.rows();
+    let cols: i32 = images.get(0)?.cols();
+
+    // shape the output radiance map.
+    unsafe {
+        out_hdri.create_rows_cols(rows, cols, opencv::core::CV_32FC3)?;
+    }
+
+    // Recover the radiance map for each channel
+    for c in 0..3 {
+        merge_internal(images, shutter_speeds, &weights, response.curve(c), None, levels, c, out_hdri)?;
+    }
+
+    log::trace!("Radiance map merging finished.");
+    Ok(())
+}
+
+// Merge linear CV_32FC3 images, e.g. developed from camera raw files with
+// raw_reader, skipping the camera response entirely.
+// saturation_masks are CV_8UC1, one per image, non-zero where the pixel
+// is saturated. Saturation can not be told from the developed colors, as
+// white balance and the color matrix move clipped values below 1.
+// The radiance is the maximum likelihood estimate under shot noise,
+// sum(I) / sum(t) over the exposures where the pixel is not saturated.
+// Pixels saturated everywhere keep the value of the shortest exposure.
+pub fn merge_linear(images: &VectorOfMat,
+                    saturation_masks: &VectorOfMat,
+                    shutter_speeds: &Vec<f32>,
+                    out_hdri: &mut Mat) -> Result<(), HdrError> {
+    log::trace!("Linear radiance map merging started.");
+
+    check_images(images, opencv::core::CV_32FC3)?;
+    check_exposures(images, shutter_speeds)?;
+    check_images(saturation_masks, opencv::core::CV_8UC1)?;
+    if saturation_masks.len() != images.len() {
+        return Err(HdrError::InvalidArgument(format!(
+            "Got {} saturation masks for {} images.", saturation_masks.len(), images.len())));
+    }
+    let image_size = (images.get(0)?.rows(), images.get(0)?.cols());
+    let mask_size = (saturation_masks.get(0)?.rows(), saturation_masks.get(0)?.cols());
+    if mask_size != image_size {
+        return Err(HdrError::MismatchedImageSize { expected: image_size, actual: mask_size });
+    }
+
+    let rows: i32 = images.get(0)?.rows();
+    let cols: i32 = images.get(0)?.cols();
+    let image_num: usize = images.len();
+
+    let mut image_list: Vec<Mat> = Vec::with_capacity(image_num);
+    let mut mask_list: Vec<Mat> = Vec::with_capacity(image_num);
+    let mut shortest: usize = 0;
+    for p in 0..image_num {
+        image_list.push(images.get(p)?);
+        mask_list.push(saturation_masks.get(p)?);
+        if shutter_speeds[p] < shutter_speeds[shortest] {
+            shortest = p;
+        }
+    }
+
+    unsafe {
+        out_hdri.create_rows_cols(rows, cols, opencv::core::CV_32FC3)?;
+    }
+
+    for row in 0..rows {
+        for col in 0..cols {
+            let mut sum_value: [f32; 3] = [0.0; 3];
+            let mut sum_time: f32 = 0.0;
+            for p in 0..image_num {
+                if *mask_list[p].at_2d::<u8>(row, col)? > 0 {
+                    continue;
+                }
+                let pixel: Vec3f = *image_list[p].at_2d::<Vec3f>(row, col)?;
+                for c in 0..3 {
+                    sum_value[c] += pixel[c];
+                }
+                sum_time += shutter_speeds[p];
+            }
+
+            let radiance = out_hdri.at_2d_mut::<Vec3f>(row, col)?;
+            if sum_time > 0.0 {
+                for c in 0..3 {
+                    radiance[c] = sum_value[c] / sum_time;
+                }
+            } else {
+                let pixel: Vec3f = *image_list[shortest].at_2d::<Vec3f>(row, col)?;
+                for c in 0..3 {
+                    radiance[c] = pixel[c] / shutter_speeds[shortest];
+                }
+            }
+        }
+    }
+
+    log::trace!("Linear radiance map merging finished.");
+    Ok(())
+}
+
+// Merge a bracket whose scene moved between the shots.
+// The image at index reference is trusted, and a pixel of any other image
+// is dropped from the merge when its radiance, in log domain, differs from
+// the reference radiance by more than threshold in the channels well exposed
+// in both images. Pixels saturated or underexposed in the reference can not
+// be checked and are merged as usual.
+//
+// out_ghost_mask receives a CV_8UC1 mask, 255 where at least one image
+// was dropped, for inspection of the detected ghosts.
+pub fn merge_with_ghost_removal(images: &VectorOfMat,
+                                shutter_speeds: &Vec<f32>,
+                                response: &CameraResponse,
+                                reference: usize,
+                                threshold: f32,
+                                out_hdri: &mut Mat,
+                                out_ghost_mask: &mut Mat) -> Result<(), HdrError> {
+    log::trace!("Radiance map merging with ghost removal started.");
+
+    let levels = check_merge_inputs(images, shutter_speeds, response)?;
+    if reference >= images.len() {
+        return Err(HdrError::InvalidArgument(
+            format!("Reference image {} is out of the {} images.", reference, images.len())));
+    }
+    if threshold <= 0.0 {
+        return Err(HdrError::InvalidArgument(
+            format!("Ghost threshold must be positive, got {}.", threshold)));
+    }
+
+    let weights: Vec<f32> = generate_hat_weights(levels);
+
+    let rows: i32 = images.get(0)?.rows();
+    let cols: i32 = images.get(0)?.cols();
+
+    let mut masks: Vec<Mat> = Vec::with_capacity(images.len());
+    detect_ghosts(images, shutter_speeds, response, &weights, reference, threshold, levels, &mut masks, out_ghost_mask)?;
+
+    unsafe {
+        out_hdri.create_rows_cols(rows, cols, opencv::core::CV_32FC3)?;
+    }
+
+    for c in 0..3 {
+        merge_internal(images, shutter_speeds, &weights, response.curve(c), Some(&masks), levels, c, out_hdri)?;
+    }
+
+    log::trace!("Radiance map merging with ghost removal finished.");
+    Ok(())
+}
+
+// Check the inputs of a merge and return the number of pixel values of the images.
+fn check_merge_inputs(images: &VectorOfMat,
+                      shutter_speeds: &Vec<f32>,
+                      response: &CameraResponse) -> Result<usize, HdrError> {
+    let levels = check_ldr_images(images)?;
+    check_exposures(images, shutter_speeds)?;
+    if response.levels() != levels {
+        return Err(HdrError::InvalidArgument(
+            format!("Images with {} levels need a response curve with as many levels, got {}.",
+                    levels, response.levels())));
+    }
+
+    Ok(levels)
+}
+
+// Build a CV_8UC1 weight mask for every image, 0 where the pixel is a ghost
+// and 255 otherwise, by comparing each image to the reference exposure.
+fn detect_ghosts(images: &VectorOfMat,
+                 shutter_speeds: &Vec<f32>,
+                 response: &CameraResponse,
+                 weights: &[f32],
+                 reference: usize,
+                 threshold: f32,
+                 levels: usize,
+                 out_masks: &mut Vec<Mat>,
+                 out_ghost_mask: &mut Mat) -> Result<(), HdrError> {
+    let rows = images.get(0)?.rows();
+    let cols = images.get(0)?.cols();
+    let image_num: usize = images.len();
+
+    let mut image_list: Vec<Mat> = Vec::with_capacity(image_num);
+    let mut log_shutter_speeds: Vec<f32> = Vec::with_capacity(image_num);
+    out_masks.clear();
+    for p in 0..image_num {
+        image_list.push(images.get(p)?);
+        log_shutter_speeds.push(shutter_speeds[p].ln());
+        out_masks.push(Mat::new_rows_cols_with_default(rows, cols, opencv::core::CV_8UC1, Scalar_::all(255.0))?);
+    }
+    *out_ghost_mask = Mat::zeros(rows, cols, opencv::core::CV_8UC1)?.to_mat()?;
+
+    let mut ghost_num: usize = 0;
+    for row in 0..rows {
+        for col in 0..cols {
+            let mut z_ref: [usize; 3] = [0; 3];
+            for c in 0..3 {
+                z_ref[c] = get_channel_value(&image_list[reference], row, col, c, levels)?;
+            }
+            for p in 0..image_num {
+                if p == reference {
+                    continue;
+                }
+
+                let mut sum_diff: f32 = 0.0;
+                let mut count: usize = 0;
+                for c in 0..3 {
+                    let z = get_channel_value(&image_list[p], row, col, c, levels)?;
+                    if weights[z_ref[c]] <= 0.0 || weights[z] <= 0.0 {
+                        continue;
+                    }
+                    let log_radiance_ref = response.log_exposure(c, z_ref[c]) - log_shutter_speeds[reference];
+                    let log_radiance = response.log_exposure(c, z) - log_shutter_speeds[p];
+                    sum_diff += (log_radiance - log_radiance_ref).abs();
+                    count += 1;
+                }
+
+                if count > 0 && sum_diff / count as f32 > threshold {
+                    *out_masks[p].at_2d_mut::<u8>(row, col)? = 0;
+                    *out_ghost_mask.at_2d_mut::<u8>(row, col)? = 255;
+                    ghost_num += 1;
+                }
+            }
+        }
+    }
+
+    log::info!("Dropped {} ghost pixels from the merge.", ghost_num);
+    Ok(())
+}
+
+fn merge_internal(images: &VectorOfMat,
+                  shutter_speeds: &Vec<f32>,
+                  weights: &[f32],
+                  g: &[f32],
+                  masks: Option<&Vec<Mat>>,
+                  levels: usize,
+                  channel: usize,
+                  out_hdri: &mut Mat) -> Result<(), HdrError> {
+    let rows = images.get(0)?.rows();
+    let cols = images.get(0)?.cols();
+    let image_num: usize = images.len();
+
+    let mut log_shutter_speeds: Vec<f32> = Vec::with_capacity(image_num);
+    for p in 0..image_num {
+        log_shutter_speeds.push(shutter_speeds[p].ln());
+    }
+
+    let mut image_list: Vec<Mat> = Vec::with_capacity(image_num);
+    for p in 0..image_num {
+        image_list.push(images.get(p)?);
+    }
+
+    log::trace!("Starting recovering for channel {}.", channel);
+    for row in 0..rows {
+        for col in 0..cols {
+            let mut sum_weight: f32 = 0.0;
+            let mut sum_radiance: f32 = 0.0;
+            for p in 0..image_num {
+                if let Some(masks) = masks {
+                    if *masks[p].at_2d::<u8>(row, col)? == 0 {
+                        continue;
+                    }
+                }
+                let z = get_channel_value(&image_list[p], row, col, channel, levels)?;
+                sum_weight += weights[z];
+                sum_radiance += weights[z] * (g[z] - log_shutter_speeds[p]);
+            }
+            out_hdri.at_2d_mut::<Vec3f>(row, col)?[channel] = (sum_radiance / (sum_weight+0.0001)).exp();
+        }
+    }
+
+    Ok(())
+}
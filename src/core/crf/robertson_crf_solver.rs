@@ -0,0 +1,213 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat, Vec3f};
+use opencv::prelude::{MatTrait, Vector};
+use opencv::types::VectorOfMat;
+use std::time::SystemTime;
+
+use crate::base::math_utils;
+use crate::base::math_utils::{bin_pixel_value, generate_hat_weights};
+use crate::base::opencv_utils::{check_exposures, check_ldr_images, get_channel_value};
+use crate::core::crf::camera_response::CameraResponse;
+use crate::core::crf::radiance_map_merger;
+use crate::error::HdrError;
+
+// Robertson et al.'s iterative maximum-likelihood CRF estimation.
+// Unlike Debevec's method it uses every pixel of the bracket instead of
+// a random sampling, alternating between estimating the radiance map
+// with a fixed response and the response with a fixed radiance map.
+// 16-bit brackets are solved on 256 bins, most of the 65536 values having
+// too few pixels, then merged again with the interpolated response.
+pub fn solve(images: &VectorOfMat,
+             shutter_speeds: &Vec<f32>,
+             max_iterations: i32,
+             threshold: f32,
+             out_hdri: &mut Mat) -> Result<CameraResponse, HdrError> {
+
+    log::trace!("Robertson crf_solver started.");
+
+    let levels = check_ldr_images(images)?;
+    check_exposures(images, shutter_speeds)?;
+    if max_iterations < 1 {
+        return Err(HdrError::InvalidArgument(
+            format!("Robertson solver needs at least 1 iteration, got {}.", max_iterations)));
+    }
+
+    let start = SystemTime::now();
+
+    // generate weights with a hat function.
+    let weights: Vec<f32> = generate_hat_weights(256);
+
+    let rows: i32 = images.get(0)?.rows();
+    let cols: i32 = images.get(0)?.cols();
+
+    // shape the output radiance map.
+    unsafe {
+        out_hdri.create_rows_cols(rows, cols, opencv::core::CV_32FC3)?;
+    }
+
+    // Solve the response curve and the radiance map for each channel
+    let mut curves: [Vec<f32>; 3] = [Vec::new(), Vec::new(), Vec::new()];
+    for c in 0..3 {
+        let pixels: Vec<Vec<u8>> = collect_channel(images, c, levels)?;
+        let mut radiance: Vec<f32> = Vec::new();
+        let response: Vec<f32> = solve_internal(&pixels, shutter_speeds, &weights,
+                                                max_iterations, threshold, c, &mut radiance)?;
+
+        for row in 0..rows {
+            for col in 0..cols {
+                out_hdri.at_2d_mut::<Vec3f>(row, col)?[c] = radiance[(row * cols + col) as usize];
+            }
+        }
+
+        // The camera response stores log exposures as Debevec's g(z).
+        let mut g: Vec<f32> = Vec::with_capacity(256);
+        for z in 0..256 {
+            g.push(response[z].max(math_utils::EPSILON as f32).ln());
+        }
+        curves[c] = g;
+    }
+
+    let mut response = CameraResponse::new(curves)?;
+    if levels != 256 {
+        response = response.resample(levels)?;
+        radiance_map_merger::merge(images, shutter_speeds, &response, out_hdri)?;
+    }
+
+    let duration = start.elapsed().map(|d| d.as_secs()).unwrap_or(0);
+    log::trace!("Robertson crf_solver ended: {} secs.", duration);
+    Ok(response)
+}
+
+fn solve_internal(pixels: &Vec<Vec<u8>>,
+                  shutter_speeds: &Vec<f32>,
+                  weights: &[f32],
+                  max_iterations: i32,
+                  threshold: f32,
+                  channel: usize,
+                  out_radiance: &mut Vec<f32>) -> Result<Vec<f32>, HdrError> {
+
+    log::trace!("Solving Robertson CRF for channel {}.", channel);
+
+    let image_num: usize = pixels.len();
+    let pixel_num: usize = pixels[0].len();
+
+    // Start from a linear response normalized at the middle pixel value.
+    let mut response: Vec<f32> = Vec::with_capacity(256);
+    for z in 0..256 {
+        response.push(z as f32 / 128.0);
+    }
+
+    for iteration in 0..max_iterations {
+        compute_radiance(pixels, shutter_speeds, weights, &response, out_radiance);
+
+        // Each response value is the mean exposure of all pixels having this value.
+        let mut sum_exposure: Vec<f64> = vec![0.0; 256];
+        let mut count: Vec<usize> = vec![0; 256];
+        for p in 0..image_num {
+            let shutter_speed = shutter_speeds[p] as f64;
+            for i in 0..pixel_num {
+                let z = pixels[p][i] as usize;
+                sum_exposure[z] += shutter_speed * out_radiance[i] as f64;
+                count[z] += 1;
+            }
+        }
+
+        let mut new_response: Vec<f32> = response.clone();
+        for z in 0..256 {
+            if count[z] > 0 {
+                new_response[z] = (sum_exposure[z] / count[z] as f64) as f32;
+            }
+        }
+
+        let middle = new_response[128];
+        if middle > 0.0 {
+            for z in 0..256 {
+                new_response[z] /= middle;
+            }
+        }
+
+        let mut diff: f32 = 0.0;
+        for z in 0..256 {
+            diff += (new_response[z] - response[z]).powi(2);
+        }
+        diff /= 256.0;
+        response = new_response;
+
+        log::trace!("Robertson iteration {} for channel {}: {}.", iteration, channel, diff);
+        if diff < threshold {
+            break;
+        }
+    }
+
+    compute_radiance(pixels, shutter_speeds, weights, &response, out_radiance);
+
+    log::trace!("Finishing solving Robertson CRF for channel {}.", channel);
+    Ok(response)
+}
+
+fn compute_radiance(pixels: &Vec<Vec<u8>>,
+                    shutter_speeds: &Vec<f32>,
+                    weights: &[f32],
+                    response: &[f32],
+                    out_radiance: &mut Vec<f32>) {
+    let image_num: usize = pixels.len();
+    let pixel_num: usize = pixels[0].len();
+
+    out_radiance.resize(pixel_num, 0.0);
+    for i in 0..pixel_num {
+        let mut sum_radiance: f32 = 0.0;
+        let mut sum_weight: f32 = 0.0;
+        for p in 0..image_num {
+            let z = pixels[p][i] as usize;
+            sum_radiance += weights[z] * shutter_speeds[p] * response[z];
+            sum_weight += weights[z] * shutter_speeds[p] * shutter_speeds[p];
+        }
+        // Exposure times may be tiny, so we can not simply add an epsilon
+        // to the denominator as the Debevec merge does.
+        if sum_weight > 0.0 {
+            out_radiance[i] = sum_radiance / sum_weight;
+        } else {
+            // Clipped or black in every exposure. Use the shortest exposure
+            // of highlights, as merge_linear does, so that clipped highlights
+            // do not turn black, and the longest exposure of shadows.
+            let mut shortest: usize = 0;
+            let mut longest: usize = 0;
+            for p in 1..image_num {
+                if shutter_speeds[p] < shutter_speeds[shortest] {
+                    shortest = p;
+                }
+                if shutter_speeds[p] > shutter_speeds[longest] {
+                    longest = p;
+                }
+            }
+            let fallback = if pixels[shortest][i] >= 128 { shortest } else { longest };
+            let z = pixels[fallback][i] as usize;
+            out_radiance[i] = response[z] / shutter_speeds[fallback];
+        }
+    }
+}
+
+// Copy one channel of every image into a flat buffer, as the
+// solver walks through all the pixels at each iteration.
+// 16-bit values are binned to 8 bits.
+fn collect_channel(images: &VectorOfMat,
+                   channel: usize,
+                   levels: usize) -> Result<Vec<Vec<u8>>, HdrError> {
+    let mut pixels: Vec<Vec<u8>> = Vec::with_capacity(images.len());
+    for p in 0..images.len() {
+        let cur_image: Mat = images.get(p)?;
+        let rows = cur_image.rows();
+        let cols = cur_image.cols();
+        let mut cur_pixels: Vec<u8> = Vec::with_capacity((rows * cols) as usize);
+        for row in 0..rows {
+            for col in 0..cols {
+                let z = get_channel_value(&cur_image, row, col, channel, levels)?;
+                cur_pixels.push(bin_pixel_value(z, levels, 256) as u8);
+            }
+        }
+        pixels.push(cur_pixels);
+    }
+
+    Ok(pixels)
+}
@@ -0,0 +1,179 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat, MatExprTrait, Scalar_, Size, Vec3f, BORDER_DEFAULT};
+use opencv::prelude::{MatTrait, Vector};
+use opencv::types::VectorOfMat;
+
+use crate::base::opencv_utils::{check_ldr_images};
+use crate::error::HdrError;
+
+// Standard deviation of the gaussian curve measuring
+// how close a pixel value is to the middle intensity.
+const WELL_EXPOSEDNESS_SIGMA: f32 = 0.2;
+// Added to every weight, so that pixels weighted 0 in every exposure,
+// e.g. grey, clipped or flat ones, are averaged instead of turning black.
+const WEIGHT_EPSILON: f64 = 1e-12;
+
+// Mertens et al.'s exposure fusion. It blends an aligned bracket into
+// an 8-bit image directly, weighting each pixel by its contrast,
+// saturation and well-exposedness, without recovering the camera
+// response nor constructing a radiance map.
+pub fn fuse(images: &VectorOfMat,
+            contrast_weight: f32,
+            saturation_weight: f32,
+            exposedness_weight: f32,
+            out_ldr: &mut Mat) -> Result<(), HdrError> {
+    log::trace!("Starting exposure fusion: Mertens.");
+
+    let levels = check_ldr_images(images)?;
+
+    let image_num = images.len();
+    let rows = images.get(0)?.rows();
+    let cols = images.get(0)?.cols();
+
+    // Step1: Compute the weight map of every image.
+    let mut float_images: Vec<Mat> = Vec::with_capacity(image_num);
+    let mut weights: Vec<Mat> = Vec::with_capacity(image_num);
+    let mut weight_sum: Mat = Mat::zeros(rows, cols, opencv::core::CV_32FC1)?.to_mat()?;
+    for i in 0..image_num {
+        let mut float_image: Mat = Mat::default()?;
+        images.get(i)?.convert_to(&mut float_image, opencv::core::CV_32FC3, 1.0 / (levels - 1) as f64, 0.0)?;
+
+        let mut raw_weight: Mat = Mat::default()?;
+        compute_weight(&float_image, contrast_weight, saturation_weight, exposedness_weight, &mut raw_weight)?;
+        let mut weight: Mat = Mat::default()?;
+        opencv::core::add(&raw_weight, &Scalar_::all(WEIGHT_EPSILON), &mut weight, &opencv::core::no_array()?, -1)?;
+
+        let mut tmp_sum: Mat = Mat::default()?;
+        opencv::core::add(&weight_sum, &weight, &mut tmp_sum, &opencv::core::no_array()?, -1)?;
+        weight_sum = tmp_sum;
+
+        float_images.push(float_image);
+        weights.push(weight);
+    }
+
+    // Step2: Normalize weights so that they sum to one at each pixel.
+    for i in 0..image_num {
+        let mut normalized_weight: Mat = Mat::default()?;
+        opencv::core::divide2(&weights[i], &weight_sum, &mut normalized_weight, 1.0, -1)?;
+        weights[i] = normalized_weight;
+    }
+
+    // Step3: Blend the laplacian pyramids of images with
+    // the gaussian pyramids of weights.
+    let max_level: usize = ((rows.min(cols) as f32).log2() as usize).max(1);
+    let mut blended_pyramid: Vec<Mat> = Vec::new();
+    for i in 0..image_num {
+        let mut weight_pyramid: Vec<Mat> = Vec::new();
+        build_gaussian_pyramid(&weights[i], max_level, &mut weight_pyramid)?;
+        let mut image_pyramid: Vec<Mat> = Vec::new();
+        build_laplacian_pyramid(&float_images[i], max_level, &mut image_pyramid)?;
+
+        for level in 0..max_level {
+            let mut weight_channels: VectorOfMat = VectorOfMat::new();
+            for _c in 0..3 {
+                weight_channels.push(weight_pyramid[level].clone()?);
+            }
+            let mut weight_3c: Mat = Mat::default()?;
+            opencv::core::merge(&weight_channels, &mut weight_3c)?;
+
+            let mut weighted: Mat = Mat::default()?;
+            opencv::core::multiply(&image_pyramid[level], &weight_3c, &mut weighted, 1.0, -1)?;
+            if i == 0 {
+                blended_pyramid.push(weighted);
+            } else {
+                let mut tmp_level: Mat = Mat::default()?;
+                opencv::core::add(&blended_pyramid[level], &weighted, &mut tmp_level, &opencv::core::no_array()?, -1)?;
+                blended_pyramid[level] = tmp_level;
+            }
+        }
+    }
+
+    // Step4: Collapse the blended pyramid.
+    let mut result: Mat = blended_pyramid[max_level - 1].clone()?;
+    for level in (0..max_level-1).rev() {
+        let mut upsampled: Mat = Mat::default()?;
+        let cur_size = Size::new(blended_pyramid[level].cols(), blended_pyramid[level].rows());
+        opencv::imgproc::pyr_up(&result, &mut upsampled, cur_size, BORDER_DEFAULT)?;
+        opencv::core::add(&upsampled, &blended_pyramid[level], &mut result, &opencv::core::no_array()?, -1)?;
+    }
+
+    result.convert_to(out_ldr, opencv::core::CV_8UC3, 255.0, 0.0)?;
+
+    log::trace!("Exposure fusion finished: Mertens.");
+    Ok(())
+}
+
+fn compute_weight(src: &Mat,
+                  contrast_weight: f32,
+                  saturation_weight: f32,
+                  exposedness_weight: f32,
+                  dst: &mut Mat) -> Result<(), HdrError> {
+    let rows = src.rows();
+    let cols = src.cols();
+
+    // Contrast is the absolute response of a laplacian filter on the grey image.
+    let mut gray_image: Mat = Mat::default()?;
+    opencv::imgproc::cvt_color(src, &mut gray_image, opencv::imgproc::COLOR_BGR2GRAY, 0)?;
+    let mut laplacian: Mat = Mat::default()?;
+    opencv::imgproc::laplacian(&gray_image, &mut laplacian, opencv::core::CV_32F, 1, 1.0, 0.0, BORDER_DEFAULT)?;
+
+    unsafe {
+        dst.create_rows_cols(rows, cols, opencv::core::CV_32FC1)?;
+    }
+
+    let sigma_2: f32 = 2.0 * WELL_EXPOSEDNESS_SIGMA * WELL_EXPOSEDNESS_SIGMA;
+    for i in 0..rows {
+        for j in 0..cols {
+            let pixel: Vec3f = *src.at_2d::<Vec3f>(i, j)?;
+            let contrast: f32 = (*laplacian.at_2d::<f32>(i, j)?).abs();
+
+            // Saturation is the standard deviation among the channels.
+            let mean: f32 = (pixel[0] + pixel[1] + pixel[2]) / 3.0;
+            let mut saturation: f32 = 0.0;
+            let mut exposedness: f32 = 1.0;
+            for c in 0..3 {
+                saturation += (pixel[c] - mean) * (pixel[c] - mean);
+                exposedness *= (-(pixel[c] - 0.5) * (pixel[c] - 0.5) / sigma_2).exp();
+            }
+            saturation = (saturation / 3.0).sqrt();
+
+            *dst.at_2d_mut::<f32>(i, j)? = contrast.powf(contrast_weight) *
+                saturation.powf(saturation_weight) * exposedness.powf(exposedness_weight);
+        }
+    }
+
+    Ok(())
+}
+
+fn build_gaussian_pyramid(src: &Mat,
+                          max_level: usize,
+                          out_pyramid: &mut Vec<Mat>) -> Result<(), HdrError> {
+    out_pyramid.push(src.clone()?);
+    for level in 1..max_level {
+        let mut downsampled: Mat = Mat::default()?;
+        opencv::imgproc::pyr_down(&out_pyramid[level - 1], &mut downsampled, Size::default(), BORDER_DEFAULT)?;
+        out_pyramid.push(downsampled);
+    }
+
+    Ok(())
+}
+
+fn build_laplacian_pyramid(src: &Mat,
+                           max_level: usize,
+                           out_pyramid: &mut Vec<Mat>) -> Result<(), HdrError> {
+    let mut gaussian_pyramid: Vec<Mat> = Vec::new();
+    build_gaussian_pyramid(src, max_level, &mut gaussian_pyramid)?;
+
+    for level in 0..max_level-1 {
+        let mut upsampled: Mat = Mat::default()?;
+        let cur_size = Size::new(gaussian_pyramid[level].cols(), gaussian_pyramid[level].rows());
+        opencv::imgproc::pyr_up(&gaussian_pyramid[level + 1], &mut upsampled, cur_size, BORDER_DEFAULT)?;
+        let mut laplacian: Mat = Mat::default()?;
+        opencv::core::subtract(&gaussian_pyramid[level], &upsampled, &mut laplacian, &opencv::core::no_array()?, -1)?;
+        out_pyramid.push(laplacian);
+    }
+    out_pyramid.push(gaussian_pyramid[max_level - 1].clone()?);
+
+    Ok(())
+}
@@ -0,0 +1,5 @@
+/* Copyright 2020 Yuchen Wong */
+
+// Exposure fusion blending a bracket into an LDR image directly.
+
+pub mod mertens_exposure_fusion;
@@ -0,0 +1,9 @@
+/* Copyright 2020 Yuchen Wong */
+
+// Algorithms of HDR construction, tone-mapping and panorama stitching.
+
+pub mod alignment;
+pub mod crf;
+pub mod fusion;
+pub mod panorama;
+pub mod tonemap;
@@ -2,19 +2,22 @@
 
 use opencv::core::{ CV_8UC1, CV_8UC3, CV_32FC3, Mat, MatExpr, Vec3f, MatTrait};
 use opencv::core::prelude::{ MatExprTrait };
-use std::error::Error;
 
-#[path = "../base/opencv_utils.rs"] mod opencv_utils;
-
-use opencv_utils::{ get_pixel, set_pixel };
+use crate::base::opencv_utils::{ check_mat_type, get_pixel, set_pixel };
+use crate::error::HdrError;
 
 pub fn cylindrial_wrap(src: &Mat,
                        focal_length: f32,
                        dst: &mut Mat,
-                       dst_wrapped_indicies: &mut Mat) -> Result<(), Box<dyn Error>> {
+                       dst_wrapped_indicies: &mut Mat) -> Result<(), HdrError> {
+    check_mat_type(src, CV_8UC3)?;
+    if focal_length <= 0.0 {
+        return Err(HdrError::InvalidArgument(
+            format!("Focal length must be positive, got {}.", focal_length)));
+    }
 
     let mut float_src: Mat = Mat::default()?;
-    src.convert_to(&mut float_src, CV_32FC3, 1.0, 0.0).unwrap();
+    src.convert_to(&mut float_src, CV_32FC3, 1.0, 0.0)?;
 
     let rows = src.rows();
     let cols = src.cols();
@@ -34,8 +37,8 @@ pub fn cylindrial_wrap(src: &Mat,
 
     let mut tmp_wrapped: Mat = Mat::default()?;
     unsafe {
-        tmp_wrapped.create_rows_cols(2*cy_x_max as i32, cols, CV_32FC3).unwrap();
-        *dst_wrapped_indicies = Mat::zeros(2*cy_x_max as i32, cols, CV_8UC1).unwrap().to_mat().unwrap();
+        tmp_wrapped.create_rows_cols(2*cy_x_max as i32, cols, CV_32FC3)?;
+        *dst_wrapped_indicies = Mat::zeros(2*cy_x_max as i32, cols, CV_8UC1)?.to_mat()?;
     }
 
     for i in 0..tmp_wrapped.rows() {
@@ -62,22 +65,22 @@ pub fn cylindrial_wrap(src: &Mat,
             let xt = x - x_floor;
             let yt = y - y_floor;
 
-            let pixel00 = get_pixel::<Vec3f>(&float_src, x_ceil as i32, y_floor as i32);
-            let pixel01 = get_pixel::<Vec3f>(&float_src, x_ceil as i32, y_ceil as i32);
-            let pixel02 = get_pixel::<Vec3f>(&float_src, x_floor as i32, y_floor as i32);
-            let pixel03 = get_pixel::<Vec3f>(&float_src, x_floor as i32, y_ceil as i32);
+            let pixel00 = get_pixel::<Vec3f>(&float_src, x_ceil as i32, y_floor as i32)?;
+            let pixel01 = get_pixel::<Vec3f>(&float_src, x_ceil as i32, y_ceil as i32)?;
+            let pixel02 = get_pixel::<Vec3f>(&float_src, x_floor as i32, y_floor as i32)?;
+            let pixel03 = get_pixel::<Vec3f>(&float_src, x_floor as i32, y_ceil as i32)?;
             let mut pixel: Vec3f = Vec3f::all(0.0);
             for k in 0..3 {
                 pixel[k] = xt * yt * pixel02[k] + xt * (1.0 - yt) * pixel03[k] +
                     (1.0 - xt) * (1.0 - yt) * pixel01[k] + (1.0 - xt) * yt * pixel00[k];
             }
 
-            set_pixel::<Vec3f>(&mut tmp_wrapped, i, j, pixel);
-            set_pixel::<u8>(dst_wrapped_indicies, i, j, 1);
+            set_pixel::<Vec3f>(&mut tmp_wrapped, i, j, pixel)?;
+            set_pixel::<u8>(dst_wrapped_indicies, i, j, 1)?;
         }
     }
 
-    tmp_wrapped.convert_to(dst, CV_8UC3, 1.0, 0.0).unwrap();
+    tmp_wrapped.convert_to(dst, CV_8UC3, 1.0, 0.0)?;
 
     Ok(())
 }
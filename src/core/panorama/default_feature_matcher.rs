@@ -1,18 +1,23 @@
 // Copyright 2020 Yuchen Wong
 
 use opencv::core::{ CV_32FC1, Mat, Point, MatTrait, NORM_L2};
-use std::error::Error;
+
+use crate::base::opencv_utils::check_mat_type;
+use crate::error::HdrError;
 
 pub fn match_feature(feature_mat1: &Mat, 
              feature_mat2: &Mat,
-             threshold: f64) -> Result<Vec<Point>, Box<dyn Error>> {
+             threshold: f64) -> Result<Vec<Point>, HdrError> {
+    check_mat_type(feature_mat1, CV_32FC1)?;
+    check_mat_type(feature_mat2, CV_32FC1)?;
+
     let feature_num1 = feature_mat1.rows();
     let feature_num2 = feature_mat2.rows();
 
     let mut feature_match: Vec<Point> = Vec::new();
     let mut match_num: i32 = 0;
     for i in 0..feature_num2 {
-        let cur_feature2 = feature_mat2.row(i).unwrap();
+        let cur_feature2 = feature_mat2.row(i)?;
 
         let mut first_match_value = std::f64::MAX;
         let mut first_match: i32 = 0;
@@ -20,9 +25,9 @@ pub fn match_feature(feature_mat1: &Mat,
         let mut _second_match: i32 = 0;
 
         for j in 0..feature_num1 {
-            let cur_feature1 = feature_mat1.row(j).unwrap();
+            let cur_feature1 = feature_mat1.row(j)?;
             let norm = opencv::core::norm2(&cur_feature1, &cur_feature2,
-                                           NORM_L2, &Mat::default()?).unwrap();
+                                           NORM_L2, &Mat::default()?)?;
             if norm < first_match_value {
                 second_match_value = first_match_value;
                 _second_match = first_match;
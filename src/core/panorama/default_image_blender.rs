@@ -2,19 +2,26 @@
 
 use opencv::core::{ Mat, MatTrait, Point, Vec3b, Vec3f, CV_8UC1, CV_8UC3, CV_32FC3 };
 use opencv::core::prelude::{ MatExprTrait };
-use std::error::Error;
 
-#[path="../base/opencv_utils.rs"] mod opencv_utils;
-
-use opencv_utils::{ get_pixel, set_pixel };
+use crate::base::opencv_utils::{ get_pixel, set_pixel };
+use crate::error::HdrError;
 
 pub fn blend_image(images: &Vec<Mat>,
                    wrapped_indicies: &Vec<Mat>,
                    alignments: &Vec<Point>,
-                   out_panorama: &mut Mat) -> Result<(), Box<dyn Error>> {
+                   out_panorama: &mut Mat) -> Result<(), HdrError> {
     log::trace!("Image blending: Start.");
 
     let image_num = images.len();
+    if image_num < 2 {
+        return Err(HdrError::InvalidArgument(
+            format!("Blending needs at least 2 images, got {}.", image_num)));
+    }
+    if wrapped_indicies.len() != image_num || alignments.len() != image_num - 1 {
+        return Err(HdrError::InvalidArgument(
+            format!("Got {} images, {} wrapped indicies and {} alignments.",
+                    image_num, wrapped_indicies.len(), alignments.len())));
+    }
     let mut accumulated_offset: Vec<Point> = Vec::new();
 
     let mut min_dy: i32 = 0;
@@ -48,8 +55,8 @@ pub fn blend_image(images: &Vec<Mat>,
     all_height += max_dy;
 
     log::info!("Constrcuting panorama with size: ({}, {}).", all_width, all_height);
-    let mut panorama = Mat::zeros(all_height, all_width, CV_32FC3).unwrap().to_mat().unwrap();
-    let mut panorama_weight = Mat::zeros(all_height, all_width, CV_8UC1).unwrap().to_mat().unwrap();
+    let mut panorama = Mat::zeros(all_height, all_width, CV_32FC3)?.to_mat()?;
+    let mut panorama_weight = Mat::zeros(all_height, all_width, CV_8UC1)?.to_mat()?;
     let mut accumulate_width: i32 = 0;
     for i in 0..image_num {
         let cur_image = &images[i];
@@ -70,31 +77,31 @@ pub fn blend_image(images: &Vec<Mat>,
 
         for sr in 0..cur_rows {
             for sc in 0..cur_cols {
-                if get_pixel::<u8>(cur_image_indicies, sr, sc) == 0 {
+                if get_pixel::<u8>(cur_image_indicies, sr, sc)? == 0 {
                     continue;
                 }
-                let cur_status = get_pixel::<u8>(&panorama_weight, start_y+sr, start_x+sc);
-                let cur_pixel = get_pixel::<Vec3b>(&cur_image, sr, sc);
+                let cur_status = get_pixel::<u8>(&panorama_weight, start_y+sr, start_x+sc)?;
+                let cur_pixel = get_pixel::<Vec3b>(&cur_image, sr, sc)?;
                 if cur_status == 0 {
-                    set_pixel::<u8>(&mut panorama_weight, start_y+sr, start_x+sc, 1);
+                    set_pixel::<u8>(&mut panorama_weight, start_y+sr, start_x+sc, 1)?;
                     let mut pixel = Vec3f::all(0.0);
                     pixel[0] += cur_pixel[0] as f32;
                     pixel[1] += cur_pixel[1] as f32;
                     pixel[2] += cur_pixel[2] as f32;
-                    set_pixel::<Vec3f>(&mut panorama, start_y+sr, start_x+sc, pixel);
+                    set_pixel::<Vec3f>(&mut panorama, start_y+sr, start_x+sc, pixel)?;
                 } else {
-                    let panorama_pixel = get_pixel::<Vec3f>(&panorama, start_y+sr, start_x+sc);
+                    let panorama_pixel = get_pixel::<Vec3f>(&panorama, start_y+sr, start_x+sc)?;
                     let mut pixel = Vec3f::all(0.0);
                     pixel[0] += cur_pixel[0] as f32 * 0.5 + panorama_pixel[0] * 0.5;
                     pixel[1] += cur_pixel[1] as f32 * 0.5 + panorama_pixel[1] * 0.5;
                     pixel[2] += cur_pixel[2] as f32 * 0.5 + panorama_pixel[2] * 0.5;
-                    set_pixel::<Vec3f>(&mut panorama, start_y+sr, start_x+sc, pixel);
+                    set_pixel::<Vec3f>(&mut panorama, start_y+sr, start_x+sc, pixel)?;
                 }
             }
         }
     }
 
-    panorama.convert_to(out_panorama, CV_8UC3, 1.0, 0.0).unwrap();
+    panorama.convert_to(out_panorama, CV_8UC3, 1.0, 0.0)?;
 
     log::trace!("Panorama construction finished.");
 
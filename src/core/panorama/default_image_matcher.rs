@@ -3,14 +3,18 @@
 use opencv::core::{ Mat, MatTrait, Point, Scalar };
 use rand::Rng;
 use std::cmp;
-use std::error::Error;
+
+use crate::error::HdrError;
 
 pub fn match_image(image1: &mut Mat,
                    image2: &mut Mat,
                    features1: &Vec<Point>,
                    features2: &Vec<Point>,
-                   feature_matches: &Vec<Point>) -> Result<Point, Box<dyn Error>> {
+                   feature_matches: &Vec<Point>) -> Result<Point, HdrError> {
     let matches_number = feature_matches.len();
+    if matches_number == 0 {
+        return Err(HdrError::TooFewMatches { required: 1, found: 0 });
+    }
     let k = cmp::max(500, 4 * matches_number as i32);
 
     let mut rng = rand::thread_rng();
@@ -51,8 +55,8 @@ pub fn match_image(image1: &mut Mat,
             //alignment = alignment - offset;
         }
     }
-    opencv::imgproc::circle(image1, features1[feature_match.x as usize], 5, Scalar::new(0.0, 255.0, 0.0, 1.0), 1, 8, 0).unwrap();
-    opencv::imgproc::circle(image2, features2[feature_match.y as usize], 5, Scalar::new(0.0, 255.0, 0.0, 1.0), 1, 8, 0).unwrap();
+    opencv::imgproc::circle(image1, features1[feature_match.x as usize], 5, Scalar::new(0.0, 255.0, 0.0, 1.0), 1, 8, 0)?;
+    opencv::imgproc::circle(image2, features2[feature_match.y as usize], 5, Scalar::new(0.0, 255.0, 0.0, 1.0), 1, 8, 0)?;
 
     Ok(alignment)
 }
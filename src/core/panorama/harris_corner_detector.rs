@@ -3,22 +3,21 @@
 use opencv::core::{ CV_8UC3, CV_32FC1, Mat, Point, Scalar, Size, Vec3b, BORDER_DEFAULT, NORM_MINMAX };
 use opencv::imgproc::{ gaussian_blur, spatial_gradient, COLOR_BGR2GRAY };
 use opencv::prelude::{ MatTrait, Vector };
-use std::error::Error;
 use std::vec::Vec;
 
-#[path = "../base/opencv_utils.rs"] mod opencv_utils;
-#[path = "../base/math_utils.rs"] mod math_utils;
-
-use opencv_utils::{ get_pixel };
+use crate::base::opencv_utils::{ check_mat_type, get_pixel };
+use crate::error::HdrError;
 
 pub fn harris_detect_corner(src: &Mat,
                             block_size: i32,
                             k: f64,
                             threshold: f32,
-                            cut_edge: bool) -> Result<Vec<Point>, Box<dyn Error>> {
+                            cut_edge: bool) -> Result<Vec<Point>, HdrError> {
+    check_mat_type(src, CV_8UC3)?;
+
     let mut buffer: Mat = Mat::default()?;
     let mut gray_image: Mat = Mat::default()?;
-    opencv::imgproc::cvt_color(src, &mut gray_image, COLOR_BGR2GRAY, 0).unwrap();
+    opencv::imgproc::cvt_color(src, &mut gray_image, COLOR_BGR2GRAY, 0)?;
 
     // Step1: Compute Ix, Iy
     let mut ix: Mat = Mat::default()?;
@@ -26,45 +25,45 @@ pub fn harris_detect_corner(src: &Mat,
     let mut buffer_x = Mat::default()?;
     let mut buffer_y = Mat::default()?;
 
-    spatial_gradient(&gray_image, &mut buffer_x, &mut buffer_y, 3, BORDER_DEFAULT).unwrap();
-    buffer_x.convert_to(&mut ix, CV_32FC1, 1.0, 0.0).unwrap();
-    buffer_y.convert_to(&mut iy, CV_32FC1, 1.0, 0.0).unwrap();
+    spatial_gradient(&gray_image, &mut buffer_x, &mut buffer_y, 3, BORDER_DEFAULT)?;
+    buffer_x.convert_to(&mut ix, CV_32FC1, 1.0, 0.0)?;
+    buffer_y.convert_to(&mut iy, CV_32FC1, 1.0, 0.0)?;
     
     // Step2: Compute Ix^2 Iy^2 IxIy
     let mut ix2: Mat = Mat::default()?;
-    opencv::core::multiply(&ix, &ix, &mut ix2, 1.0, -1).unwrap();
+    opencv::core::multiply(&ix, &ix, &mut ix2, 1.0, -1)?;
     let mut iy2: Mat = Mat::default()?;
-    opencv::core::multiply(&iy, &iy, &mut iy2, 1.0, -1).unwrap();
+    opencv::core::multiply(&iy, &iy, &mut iy2, 1.0, -1)?;
     let mut ixiy: Mat = Mat::default()?;
-    opencv::core::multiply(&ix, &iy, &mut ixiy, 1.0, -1).unwrap();
+    opencv::core::multiply(&ix, &iy, &mut ixiy, 1.0, -1)?;
 
     // Step3: Gaussian filter on ix2, iy2, ixiy
     let mut sx2: Mat = Mat::default()?;
     let mut sy2: Mat = Mat::default()?;
     let mut sxsy: Mat = Mat::default()?;
     gaussian_blur(&ix2, &mut sx2, Size::new(block_size, block_size), 
-                  1.0, 0.0, BORDER_DEFAULT).unwrap();
+                  1.0, 0.0, BORDER_DEFAULT)?;
     gaussian_blur(&iy2, &mut sy2, Size::new(block_size, block_size), 
-                  1.0, 0.0, BORDER_DEFAULT).unwrap();
+                  1.0, 0.0, BORDER_DEFAULT)?;
     gaussian_blur(&ixiy, &mut sxsy, Size::new(block_size, block_size), 
-                  1.0, 0.0, BORDER_DEFAULT).unwrap();
+                  1.0, 0.0, BORDER_DEFAULT)?;
 
     // Step4: Now that M = [sx2, sxsy]
     //                     [sxsy, sy2]
     // We compute the R = det(M) - k * (trace(M))^2
     let mut sx2sy2 = Mat::default()?;
-    opencv::core::multiply(&sx2, &sy2, &mut sx2sy2, 1.0, -1).unwrap();
+    opencv::core::multiply(&sx2, &sy2, &mut sx2sy2, 1.0, -1)?;
     let mut sxsy2 = Mat::default()?;
-    opencv::core::multiply(&sxsy, &sxsy, &mut sxsy2, 1.0, -1).unwrap();
+    opencv::core::multiply(&sxsy, &sxsy, &mut sxsy2, 1.0, -1)?;
     let mut det = Mat::default()?;
-    opencv::core::add_weighted(&sx2sy2, 1.0, &sxsy2, -1.0, 0.0, &mut det, -1).unwrap();
-    opencv::core::add_weighted(&sx2, 1.0, &sy2, 1.0, 0.0, &mut buffer, -1).unwrap();
+    opencv::core::add_weighted(&sx2sy2, 1.0, &sxsy2, -1.0, 0.0, &mut det, -1)?;
+    opencv::core::add_weighted(&sx2, 1.0, &sy2, 1.0, 0.0, &mut buffer, -1)?;
     let mut trace = Mat::default()?;
-    opencv::core::multiply(&buffer, &buffer, &mut trace, 1.0, -1).unwrap();
+    opencv::core::multiply(&buffer, &buffer, &mut trace, 1.0, -1)?;
     let mut R = Mat::default()?;
-    opencv::core::add_weighted(&det, 1.0, &trace, -k, 0.0, &mut buffer, -1).unwrap();
+    opencv::core::add_weighted(&det, 1.0, &trace, -k, 0.0, &mut buffer, -1)?;
     opencv::core::normalize(&buffer, &mut R, 0.0, 255.0, 
-                            NORM_MINMAX, -1, &Mat::default()?).unwrap();
+                            NORM_MINMAX, -1, &Mat::default()?)?;
 
     // Now we do the output
     let rows = src.rows();
@@ -80,14 +79,14 @@ pub fn harris_detect_corner(src: &Mat,
     }
     for i in thresh..rows-thresh {
         for j in thresh..cols-thresh {
-            let pixel = get_pixel::<f32>(&R, i, j);
+            let pixel = get_pixel::<f32>(&R, i, j)?;
             if pixel > threshold {
                 let mut is_local_maximum = true;
                 for k in 0..8 {
                     let rr = i + mx[k];
                     let cc = j + my[k];
                     if rr >=0 && rr < rows && cc >=0 && cc < cols {
-                        if get_pixel::<f32>(&R, rr, cc) > pixel {
+                        if get_pixel::<f32>(&R, rr, cc)? > pixel {
                             is_local_maximum = false;
                             break;
                         }
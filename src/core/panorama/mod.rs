@@ -0,0 +1,11 @@
+/* Copyright 2020 Yuchen Wong */
+
+// Cylindrical panorama stitching: warping, feature detection,
+// description, matching and blending.
+
+pub mod cylindrical_image_wrapper;
+pub mod default_feature_matcher;
+pub mod default_image_blender;
+pub mod default_image_matcher;
+pub mod harris_corner_detector;
+pub mod sift_feature_descriptor;
@@ -0,0 +1,183 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat};
+use opencv::prelude::MatTrait;
+
+use crate::base::math_utils;
+use crate::base::opencv_utils::check_mat_type;
+use crate::core::tonemap::tone_mapping_utils::{compute_l_w, map_luminance_with_saturation};
+use crate::error::HdrError;
+
+// Width of the histogram bins, and of the segments of the tone curve,
+// in log10 luminance.
+const BIN_WIDTH: f32 = 0.1;
+
+// The display the image is tone mapped for, with luminances in cd/m^2.
+#[derive(Clone, Copy, Debug)]
+pub struct DisplayModel {
+    pub peak_luminance: f32,
+    pub black_level: f32,
+    // Illuminance of the room in lux, reflected by the screen.
+    pub ambient_illuminance: f32,
+    // Fraction of the ambient light reflected by the screen.
+    pub reflectivity: f32,
+    pub gamma: f32,
+}
+
+impl Default for DisplayModel {
+    // A typical LCD in an office.
+    fn default() -> Self {
+        DisplayModel {
+            peak_luminance: 200.0,
+            black_level: 0.8,
+            ambient_illuminance: 400.0,
+            reflectivity: 0.01,
+            gamma: 2.2,
+        }
+    }
+}
+
+impl DisplayModel {
+    // Luminance of the darkest and brightest pixels, including the
+    // ambient light reflected by the screen.
+    fn luminance_range(&self) -> (f32, f32) {
+        let reflected: f32 = self.reflectivity * self.ambient_illuminance / std::f32::consts::PI;
+        (self.black_level + reflected, self.peak_luminance + reflected)
+    }
+
+    // The linear pixel value in [0, 1] which shows the luminance l_d,
+    // before the gamma of the display is inverted.
+    fn linear_value(&self,
+                    l_d: f32) -> f32 {
+        let (min_l_d, max_l_d) = self.luminance_range();
+        ((l_d - min_l_d) / (max_l_d - min_l_d)).max(0.0).min(1.0)
+    }
+}
+
+// Display adaptive tone mapping after Mantiuk, Daly and Kerofsky.
+// The tone curve is piecewise linear in log10 luminance, with one segment
+// per histogram bin. Its slopes keep the contrasts of the scene where the
+// histogram is dense and compress them where it is sparse, so that the
+// curve fits in the dynamic range left by the display and its ambient
+// light while the visible contrast distortion is the smallest.
+pub fn map(src: &Mat,
+           display: &DisplayModel,
+           saturation: f32,
+           out_ldr: &mut Mat) -> Result<(), HdrError> {
+    log::trace!("Starting tone mapping: Display Adaptive.");
+    check_mat_type(src, opencv::core::CV_32FC3)?;
+    if display.peak_luminance <= display.black_level || display.black_level < 0.0 ||
+       display.ambient_illuminance < 0.0 || display.reflectivity < 0.0 || display.gamma <= 0.0 {
+        return Err(HdrError::InvalidArgument(format!("Invalid display model {:?}.", display)));
+    }
+
+    let mut l_w: Mat = Mat::default()?;
+    let mut l_d: Mat = Mat::default()?;
+    compute_radiance(src, display, &mut l_w, &mut l_d)?;
+
+    // The colors are rebuilt from the linear luminance, then encoded for the display.
+    map_luminance_with_saturation(src, &l_w, &l_d, saturation, display.gamma, out_ldr)?;
+
+    log::trace!("Tone mapping finished: Display Adaptive.");
+    Ok(())
+}
+
+fn compute_radiance(src: &Mat,
+                    display: &DisplayModel,
+                    out_l_w: &mut Mat,
+                    out_radiance_map: &mut Mat) -> Result<(), HdrError> {
+    compute_l_w(src, out_l_w)?;
+
+    let rows: i32 = src.rows();
+    let cols: i32 = src.cols();
+    let mut log_l_w: Vec<f32> = Vec::with_capacity((rows * cols) as usize);
+    for i in 0..rows {
+        for j in 0..cols {
+            log_l_w.push(out_l_w.at_2d::<f32>(i, j)?.max(math_utils::EPSILON as f32).log10());
+        }
+    }
+    let min_log_l_w: f32 = log_l_w.iter().cloned().fold(f32::MAX, f32::min);
+    let max_log_l_w: f32 = log_l_w.iter().cloned().fold(f32::MIN, f32::max);
+
+    let bin_num: usize = (((max_log_l_w - min_log_l_w) / BIN_WIDTH).ceil() as usize).max(1);
+    let mut histogram: Vec<f32> = vec![0.0; bin_num];
+    for value in &log_l_w {
+        let bin = (((value - min_log_l_w) / BIN_WIDTH) as usize).min(bin_num - 1);
+        histogram[bin] += 1.0;
+    }
+    for count in histogram.iter_mut() {
+        *count /= log_l_w.len() as f32;
+    }
+
+    let (min_l_d, max_l_d) = display.luminance_range();
+    let display_range: f32 = (max_l_d / min_l_d).log10();
+    let slopes: Vec<f32> = compute_tone_curve_slopes(&histogram, display_range);
+
+    // The nodes of the tone curve, the brightest one at the peak luminance.
+    let mut nodes: Vec<f32> = vec![0.0; bin_num + 1];
+    for k in 0..bin_num {
+        nodes[k + 1] = nodes[k] + slopes[k] * BIN_WIDTH;
+    }
+    let offset: f32 = max_l_d.log10() - nodes[bin_num];
+    log::info!("Scene range {} and display range {} in log10 luminance.",
+               max_log_l_w - min_log_l_w, display_range);
+
+    unsafe {
+        out_radiance_map.create_rows_cols(rows, cols, opencv::core::CV_32FC1)?;
+    }
+    for i in 0..rows {
+        for j in 0..cols {
+            let position: f32 = (log_l_w[(i * cols + j) as usize] - min_log_l_w) / BIN_WIDTH;
+            let bin: usize = (position as usize).min(bin_num - 1);
+            let log_l_d: f32 = nodes[bin] + (position - bin as f32) * slopes[bin] * BIN_WIDTH + offset;
+            *out_radiance_map.at_2d_mut::<f32>(i, j)? = display.linear_value(10.0f32.powf(log_l_d));
+        }
+    }
+
+    Ok(())
+}
+
+// The slopes s_k of the tone curve minimizing the contrast distortion
+// sum(p_k * (1 - s_k)^2), where p_k is the probability of bin k, under
+// sum(s_k * BIN_WIDTH) <= display_range and s_k >= 0. The closed form of
+// the Lagrangian is s_k = 1 - mu / p_k, empty bins get no slope and bins
+// whose slope would be negative are dropped until all slopes are valid.
+// Contrasts are never expanded.
+fn compute_tone_curve_slopes(histogram: &Vec<f32>,
+                             display_range: f32) -> Vec<f32> {
+    let bin_num = histogram.len();
+    if bin_num as f32 * BIN_WIDTH <= display_range {
+        return vec![1.0; bin_num];
+    }
+
+    let mut active: Vec<bool> = histogram.iter().map(|p| *p > 0.0).collect();
+    let mut slopes: Vec<f32> = vec![0.0; bin_num];
+    loop {
+        let active_num = active.iter().filter(|a| **a).count();
+        if active_num == 0 {
+            return slopes;
+        }
+        let inverse_sum: f32 = (0..bin_num).filter(|k| active[*k]).map(|k| 1.0 / histogram[k]).sum();
+        let mu: f32 = (active_num as f32 - display_range / BIN_WIDTH) / inverse_sum;
+        // The occupied bins fit in the display, keep their contrasts.
+        if mu <= 0.0 {
+            return (0..bin_num).map(|k| if active[k] { 1.0 } else { 0.0 }).collect();
+        }
+
+        let mut changed = false;
+        for k in 0..bin_num {
+            if !active[k] {
+                slopes[k] = 0.0;
+                continue;
+            }
+            slopes[k] = 1.0 - mu / histogram[k];
+            if slopes[k] < 0.0 {
+                active[k] = false;
+                changed = true;
+            }
+        }
+        if !changed {
+            return slopes;
+        }
+    }
+}
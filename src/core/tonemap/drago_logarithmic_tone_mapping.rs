@@ -0,0 +1,73 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat};
+use opencv::prelude::MatTrait;
+
+use crate::base::opencv_utils::check_mat_type;
+use crate::core::tonemap::tone_mapping_utils::{compute_l_w, compute_log_average, map_luminance};
+use crate::error::HdrError;
+
+// Drago et al.'s adaptive logarithmic mapping. The luminance, scaled by
+// its log-average, is compressed with a logarithm whose base varies from
+// 2 for dark pixels to 10 for the brightest one. The bias, in (0, 1),
+// sets how fast the base grows, lower values give brighter shadows,
+// and 0.85 is the default of the paper.
+// The operator is global, a single pass over the pixels, so it is cheap
+// enough for previews.
+pub fn map(src: &Mat,
+           bias: f32,
+           out_ldr: &mut Mat) -> Result<(), HdrError> {
+    log::trace!("Starting tone mapping: Drago Logarithmic.");
+    check_mat_type(src, opencv::core::CV_32FC3)?;
+    if bias <= 0.0 || bias >= 1.0 {
+        return Err(HdrError::InvalidArgument(format!("Bias must be in (0, 1), got {}.", bias)));
+    }
+
+    let mut l_w: Mat = Mat::default()?;
+    let mut l_d: Mat = Mat::default()?;
+    compute_radiance(src, bias, &mut l_w, &mut l_d)?;
+
+    map_luminance(src, &l_w, &l_d, out_ldr)?;
+
+    log::trace!("Tone mapping finished: Drago Logarithmic.");
+    Ok(())
+}
+
+fn compute_radiance(src: &Mat,
+                    bias: f32,
+                    out_l_w: &mut Mat,
+                    out_radiance_map: &mut Mat) -> Result<(), HdrError> {
+    compute_l_w(src, out_l_w)?;
+
+    let rows: i32 = src.rows();
+    let cols: i32 = src.cols();
+
+    let l_w_hat: f32 = compute_log_average(out_l_w)?;
+    let mut l_w_max: f32 = 0.0;
+    for i in 0..rows {
+        for j in 0..cols {
+            l_w_max = l_w_max.max(*out_l_w.at_2d::<f32>(i, j)? / l_w_hat);
+        }
+    }
+    if l_w_max <= 0.0 {
+        return Err(HdrError::InvalidArgument(String::from("Radiance map is black.")));
+    }
+    log::info!("Log-average luminance {}, scaled maximum {}.", l_w_hat, l_w_max);
+
+    unsafe {
+        out_radiance_map.create_rows_cols(rows, cols, opencv::core::CV_32FC1)?;
+    }
+
+    let bias_power: f32 = bias.ln() / 0.5f32.ln();
+    let scale: f32 = 1.0 / (l_w_max + 1.0).log10();
+    for i in 0..rows {
+        for j in 0..cols {
+            let cur_l_w: f32 = (*out_l_w.at_2d::<f32>(i, j)? / l_w_hat).max(0.0);
+            let cur_l_d: f32 = scale * (cur_l_w + 1.0).ln() /
+                               (2.0 + 8.0 * (cur_l_w / l_w_max).powf(bias_power)).ln();
+            *out_radiance_map.at_2d_mut::<f32>(i, j)? = cur_l_d;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,221 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat};
+use opencv::prelude::MatTrait;
+
+use crate::base::math_utils;
+use crate::base::opencv_utils::check_mat_type;
+use crate::core::tonemap::tone_mapping_utils::{compute_l_w, map_luminance};
+use crate::error::HdrError;
+
+// Cells of padding around the bilateral grid, so that the blur kernel
+// never reads outside of it.
+const GRID_PADDING: usize = 2;
+
+// Durand and Dorsey's fast bilateral filtering for the display of HDR images.
+// The log10 luminance is split into a base layer, its edge preserving
+// bilateral filter, and a detail layer. Only the base layer is compressed,
+// to a log10 range of log10(contrast), the details are kept and scaled by
+// detail_gain, and the brightest base value is mapped to white.
+// sigma_space is the spatial scale of the filter in pixels, about 2% of
+// the image size, and sigma_range its scale in log10 luminance, about 0.4.
+pub fn map(src: &Mat,
+           contrast: f32,
+           detail_gain: f32,
+           sigma_space: f32,
+           sigma_range: f32,
+           out_ldr: &mut Mat) -> Result<(), HdrError> {
+    log::trace!("Starting tone mapping: Durand Bilateral.");
+    check_mat_type(src, opencv::core::CV_32FC3)?;
+    if contrast <= 1.0 {
+        return Err(HdrError::InvalidArgument(
+            format!("Target contrast must be greater than 1, got {}.", contrast)));
+    }
+    if sigma_space < 1.0 || sigma_range <= 0.0 {
+        return Err(HdrError::InvalidArgument(format!(
+            "Bilateral filter needs sigma space of at least 1 pixel and a positive sigma range, got {} and {}.",
+            sigma_space, sigma_range)));
+    }
+
+    let mut l_w: Mat = Mat::default()?;
+    let mut l_d: Mat = Mat::default()?;
+    compute_radiance(src, contrast, detail_gain, sigma_space, sigma_range, &mut l_w, &mut l_d)?;
+
+    map_luminance(src, &l_w, &l_d, out_ldr)?;
+
+    log::trace!("Tone mapping finished: Durand Bilateral.");
+    Ok(())
+}
+
+fn compute_radiance(src: &Mat,
+                    contrast: f32,
+                    detail_gain: f32,
+                    sigma_space: f32,
+                    sigma_range: f32,
+                    out_l_w: &mut Mat,
+                    out_radiance_map: &mut Mat) -> Result<(), HdrError> {
+    compute_l_w(src, out_l_w)?;
+
+    let rows = src.rows() as usize;
+    let cols = src.cols() as usize;
+    let mut log_l_w: Vec<f32> = Vec::with_capacity(rows * cols);
+    for i in 0..rows as i32 {
+        for j in 0..cols as i32 {
+            log_l_w.push((out_l_w.at_2d::<f32>(i, j)?.max(0.0) + math_utils::EPSILON as f32).log10());
+        }
+    }
+    // Infinite luminances are clipped to the brightest finite one, so that
+    // they can not stretch the bilateral grid.
+    let max_finite: f32 = log_l_w.iter().cloned().filter(|v| v.is_finite())
+        .fold((math_utils::EPSILON as f32).log10(), f32::max);
+    for value in log_l_w.iter_mut() {
+        if !value.is_finite() {
+            *value = max_finite;
+        }
+    }
+
+    let base: Vec<f32> = bilateral_grid_filter(&log_l_w, rows, cols, sigma_space, sigma_range);
+    let max_base: f32 = base.iter().cloned().fold(f32::MIN, f32::max);
+    let min_base: f32 = base.iter().cloned().fold(f32::MAX, f32::min);
+    let compression: f32 = if max_base > min_base {
+        (contrast.log10() / (max_base - min_base)).min(1.0)
+    } else {
+        1.0
+    };
+    log::info!("Base layer from {} to {}, compressed by {}.", min_base, max_base, compression);
+
+    unsafe {
+        out_radiance_map.create_rows_cols(rows as i32, cols as i32, opencv::core::CV_32FC1)?;
+    }
+    for i in 0..rows {
+        for j in 0..cols {
+            let index = i * cols + j;
+            let detail: f32 = log_l_w[index] - base[index];
+            let log_l_d: f32 = (base[index] - max_base) * compression + detail * detail_gain;
+            *out_radiance_map.at_2d_mut::<f32>(i as i32, j as i32)? = 10.0f32.powf(log_l_d);
+        }
+    }
+
+    Ok(())
+}
+
+// Chen, Paris and Durand's bilateral grid: the values are splatted into a
+// grid subsampled by sigma_space in space and by sigma_range in value,
+// blurred, and read back with trilinear interpolation, which costs a few
+// passes over the image whatever the sizes of the kernels.
+fn bilateral_grid_filter(values: &Vec<f32>,
+                         rows: usize,
+                         cols: usize,
+                         sigma_space: f32,
+                         sigma_range: f32) -> Vec<f32> {
+    let min_value: f32 = values.iter().cloned().fold(f32::MAX, f32::min);
+    let max_value: f32 = values.iter().cloned().fold(f32::MIN, f32::max);
+
+    let grid_rows = ((rows - 1) as f32 / sigma_space) as usize + 1 + 2 * GRID_PADDING;
+    let grid_cols = ((cols - 1) as f32 / sigma_space) as usize + 1 + 2 * GRID_PADDING;
+    let grid_depth = ((max_value - min_value) / sigma_range) as usize + 1 + 2 * GRID_PADDING;
+    let grid_index = |r: usize, c: usize, d: usize| (r * grid_cols + c) * grid_depth + d;
+
+    let mut grid_values: Vec<f32> = vec![0.0; grid_rows * grid_cols * grid_depth];
+    let mut grid_weights: Vec<f32> = vec![0.0; grid_rows * grid_cols * grid_depth];
+    for i in 0..rows {
+        for j in 0..cols {
+            let value = values[i * cols + j];
+            let r = (i as f32 / sigma_space).round() as usize + GRID_PADDING;
+            let c = (j as f32 / sigma_space).round() as usize + GRID_PADDING;
+            let d = ((value - min_value) / sigma_range).round() as usize + GRID_PADDING;
+            grid_values[grid_index(r, c, d)] += value;
+            grid_weights[grid_index(r, c, d)] += 1.0;
+        }
+    }
+
+    let dims = [grid_rows, grid_cols, grid_depth];
+    let strides = [grid_cols * grid_depth, grid_depth, 1];
+    for axis in 0..3 {
+        blur_grid_axis(&mut grid_values, dims[axis], strides[axis]);
+        blur_grid_axis(&mut grid_weights, dims[axis], strides[axis]);
+    }
+
+    let mut filtered: Vec<f32> = Vec::with_capacity(rows * cols);
+    for i in 0..rows {
+        for j in 0..cols {
+            let value = values[i * cols + j];
+            let position = [i as f32 / sigma_space + GRID_PADDING as f32,
+                            j as f32 / sigma_space + GRID_PADDING as f32,
+                            (value - min_value) / sigma_range + GRID_PADDING as f32];
+
+            let mut sum_value: f32 = 0.0;
+            let mut sum_weight: f32 = 0.0;
+            for corner in 0..8 {
+                let mut index: usize = 0;
+                let mut weight: f32 = 1.0;
+                for axis in 0..3 {
+                    let low = position[axis].floor();
+                    let fraction = position[axis] - low;
+                    let (cell, cell_weight) = if corner >> axis & 1 == 1 {
+                        (low as usize + 1, fraction)
+                    } else {
+                        (low as usize, 1.0 - fraction)
+                    };
+                    index += cell.min(dims[axis] - 1) * strides[axis];
+                    weight *= cell_weight;
+                }
+                sum_value += weight * grid_values[index];
+                sum_weight += weight * grid_weights[index];
+            }
+
+            filtered.push(if sum_weight > 0.0 { sum_value / sum_weight } else { value });
+        }
+    }
+
+    filtered
+}
+
+// Blur the grid along one axis with the [1, 4, 6, 4, 1] / 16 kernel,
+// which approximates a gaussian of one cell.
+fn blur_grid_axis(grid: &mut Vec<f32>,
+                  length: usize,
+                  stride: usize) {
+    const KERNEL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+
+    let source: Vec<f32> = grid.clone();
+    for index in 0..grid.len() {
+        let position = index / stride % length;
+        let mut sum: f32 = 0.0;
+        for k in 0..5 {
+            let offset = position as i64 + k as i64 - 2;
+            if offset >= 0 && offset < length as i64 {
+                sum += KERNEL[k] * source[(index as i64 + (offset - position as i64) * stride as i64) as usize];
+            }
+        }
+        grid[index] = sum;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::Vec3f;
+
+    #[test]
+    fn bad_pixels_stay_local() {
+        let mut src: Mat = Mat::new_rows_cols_with_default(
+            8, 8, opencv::core::CV_32FC3, opencv::core::Scalar_::all(0.5)).unwrap();
+        *src.at_2d_mut::<Vec3f>(2, 2).unwrap() = Vec3f::all(-1.0);
+        *src.at_2d_mut::<Vec3f>(5, 5).unwrap() = Vec3f::all(f32::INFINITY);
+
+        let mut ldr: Mat = Mat::default().unwrap();
+        map(&src, 5.0, 1.0, 2.0, 0.4, &mut ldr).unwrap();
+
+        // Every other pixel keeps the value of the uniform image.
+        let expected: [u8; 3] = ldr.at_2d::<opencv::core::Vec3b>(0, 0).unwrap().0;
+        assert!(expected[0] > 0);
+        for i in 0..8 {
+            for j in 0..8 {
+                if (i, j) != (2, 2) && (i, j) != (5, 5) {
+                    assert_eq!(ldr.at_2d::<opencv::core::Vec3b>(i, j).unwrap().0, expected, "{} {}", i, j);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,226 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat, Rect, Size_};
+use opencv::prelude::MatTrait;
+
+use crate::base::math_utils;
+use crate::base::opencv_utils::check_mat_type;
+use crate::core::tonemap::tone_mapping_utils::{compute_l_w, map_luminance_with_saturation};
+use crate::error::HdrError;
+
+// The coarsest level of the gradient pyramid is at least this large.
+const MIN_PYRAMID_SIZE: i32 = 32;
+// Fraction of the darkest and brightest pixels clipped when the
+// reconstructed luminance is stretched to the display range.
+const CLIP_RATIO: f32 = 0.005;
+
+// Fattal, Lischinski and Werman's gradient domain HDR compression.
+// The gradients of the log luminance are attenuated, large ones more than
+// small ones, and the luminance is reconstructed from the attenuated field
+// by solving a Poisson equation. Gradients larger than alpha times the
+// average gradient of their pyramid level are compressed with the exponent
+// beta, below 1, and smaller ones are slightly magnified. The colors are
+// restored with the given saturation.
+pub fn map(src: &Mat,
+           alpha: f32,
+           beta: f32,
+           saturation: f32,
+           out_ldr: &mut Mat) -> Result<(), HdrError> {
+    log::trace!("Starting tone mapping: Fattal Gradient Domain.");
+    check_mat_type(src, opencv::core::CV_32FC3)?;
+    if alpha <= 0.0 || beta <= 0.0 || beta > 1.0 {
+        return Err(HdrError::InvalidArgument(format!(
+            "Gradient attenuation needs a positive alpha and a beta in (0, 1], got {} and {}.", alpha, beta)));
+    }
+
+    let mut l_w: Mat = Mat::default()?;
+    let mut l_d: Mat = Mat::default()?;
+    compute_radiance(src, alpha, beta, &mut l_w, &mut l_d)?;
+
+    map_luminance_with_saturation(src, &l_w, &l_d, saturation, 1.0, out_ldr)?;
+
+    log::trace!("Tone mapping finished: Fattal Gradient Domain.");
+    Ok(())
+}
+
+fn compute_radiance(src: &Mat,
+                    alpha: f32,
+                    beta: f32,
+                    out_l_w: &mut Mat,
+                    out_radiance_map: &mut Mat) -> Result<(), HdrError> {
+    compute_l_w(src, out_l_w)?;
+
+    let rows = src.rows();
+    let cols = src.cols();
+    let mut log_l_w: Mat = Mat::default()?;
+    unsafe {
+        log_l_w.create_rows_cols(rows, cols, opencv::core::CV_32FC1)?;
+    }
+    for i in 0..rows {
+        for j in 0..cols {
+            *log_l_w.at_2d_mut::<f32>(i, j)? = (out_l_w.at_2d::<f32>(i, j)?.max(0.0) + math_utils::EPSILON as f32).ln();
+        }
+    }
+
+    // The DCT works on even sizes, the replicated border has no gradient
+    // and does not change the solution.
+    let mut padded_log_l_w: Mat = Mat::default()?;
+    opencv::core::copy_make_border(&log_l_w, &mut padded_log_l_w, 0, rows % 2, 0, cols % 2,
+                                   opencv::core::BORDER_REPLICATE, opencv::core::Scalar_::default())?;
+
+    let mut attenuation: Mat = Mat::default()?;
+    compute_attenuation(&padded_log_l_w, alpha, beta, &mut attenuation)?;
+
+    let mut divergence: Mat = Mat::default()?;
+    compute_divergence(&padded_log_l_w, &attenuation, &mut divergence)?;
+
+    let mut padded_log_l_d: Mat = Mat::default()?;
+    solve_poisson(&divergence, &mut padded_log_l_d)?;
+    let log_l_d: Mat = Mat::roi(&padded_log_l_d, Rect::new(0, 0, cols, rows))?;
+
+    // The solution is known up to a constant, stretch it to the display.
+    let mut values: Vec<f32> = Vec::with_capacity((rows * cols) as usize);
+    for i in 0..rows {
+        for j in 0..cols {
+            values.push(*log_l_d.at_2d::<f32>(i, j)?);
+        }
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let clip_count = (values.len() as f32 * CLIP_RATIO) as usize;
+    let min_l_d: f32 = values[clip_count].exp();
+    let max_l_d: f32 = values[values.len() - 1 - clip_count].exp();
+    log::info!("Reconstructed luminance from {} to {}.", min_l_d, max_l_d);
+
+    unsafe {
+        out_radiance_map.create_rows_cols(rows, cols, opencv::core::CV_32FC1)?;
+    }
+    for i in 0..rows {
+        for j in 0..cols {
+            let cur_l_d: f32 = if max_l_d > min_l_d {
+                (log_l_d.at_2d::<f32>(i, j)?.exp() - min_l_d) / (max_l_d - min_l_d)
+            } else {
+                0.5
+            };
+            *out_radiance_map.at_2d_mut::<f32>(i, j)? = cur_l_d.max(0.0).min(1.0);
+        }
+    }
+
+    Ok(())
+}
+
+// The attenuation of every gradient of the finest level, accumulated from
+// the coarsest level of a gaussian pyramid so that large scale edges are
+// attenuated as well.
+fn compute_attenuation(log_l_w: &Mat,
+                       alpha: f32,
+                       beta: f32,
+                       out_attenuation: &mut Mat) -> Result<(), HdrError> {
+    let mut pyramid: Vec<Mat> = vec![log_l_w.clone()?];
+    loop {
+        let last: &Mat = &pyramid[pyramid.len() - 1];
+        if last.rows().min(last.cols()) / 2 < MIN_PYRAMID_SIZE {
+            break;
+        }
+        let mut next_level: Mat = Mat::default()?;
+        opencv::imgproc::pyr_down(last, &mut next_level, Size_::default(), opencv::core::BORDER_DEFAULT)?;
+        pyramid.push(next_level);
+    }
+    log::info!("Gradient pyramid with {} levels.", pyramid.len());
+
+    for k in (0..pyramid.len()).rev() {
+        let level: &Mat = &pyramid[k];
+        let rows = level.rows();
+        let cols = level.cols();
+
+        // Central differences, scaled to the spacing of the finest level.
+        let scale: f32 = 1.0 / (1 << (k + 1)) as f32;
+        let mut magnitudes: Vec<f32> = Vec::with_capacity((rows * cols) as usize);
+        for i in 0..rows {
+            for j in 0..cols {
+                let gx = *level.at_2d::<f32>(i, (j + 1).min(cols - 1))? - *level.at_2d::<f32>(i, (j - 1).max(0))?;
+                let gy = *level.at_2d::<f32>((i + 1).min(rows - 1), j)? - *level.at_2d::<f32>((i - 1).max(0), j)?;
+                magnitudes.push((gx * gx + gy * gy).sqrt() * scale);
+            }
+        }
+        let level_alpha: f32 = alpha * magnitudes.iter().sum::<f32>() / magnitudes.len() as f32;
+
+        let mut scaling: Mat = if k + 1 == pyramid.len() {
+            Mat::new_rows_cols_with_default(rows, cols, opencv::core::CV_32FC1, opencv::core::Scalar_::all(1.0))?
+        } else {
+            let mut upsampled: Mat = Mat::default()?;
+            opencv::imgproc::resize(&*out_attenuation, &mut upsampled, Size_::new(cols, rows),
+                                    0.0, 0.0, opencv::imgproc::INTER_LINEAR)?;
+            upsampled
+        };
+
+        for i in 0..rows {
+            for j in 0..cols {
+                let magnitude = magnitudes[(i * cols + j) as usize] + math_utils::EPSILON as f32;
+                let phi: f32 = if level_alpha > 0.0 {
+                    (level_alpha / magnitude) * (magnitude / level_alpha).powf(beta)
+                } else {
+                    1.0
+                };
+                *scaling.at_2d_mut::<f32>(i, j)? *= phi;
+            }
+        }
+        *out_attenuation = scaling;
+    }
+
+    Ok(())
+}
+
+// Divergence of the attenuated forward differences of log_l_w, with no
+// flow across the image border.
+fn compute_divergence(log_l_w: &Mat,
+                      attenuation: &Mat,
+                      out_divergence: &mut Mat) -> Result<(), HdrError> {
+    let rows = log_l_w.rows();
+    let cols = log_l_w.cols();
+
+    let gradient = |i: i32, j: i32, di: i32, dj: i32| -> Result<f32, HdrError> {
+        if i < 0 || j < 0 || i + di >= rows || j + dj >= cols {
+            return Ok(0.0);
+        }
+        let difference = *log_l_w.at_2d::<f32>(i + di, j + dj)? - *log_l_w.at_2d::<f32>(i, j)?;
+        let phi = 0.5 * (*attenuation.at_2d::<f32>(i + di, j + dj)? + *attenuation.at_2d::<f32>(i, j)?);
+        Ok(difference * phi)
+    };
+
+    unsafe {
+        out_divergence.create_rows_cols(rows, cols, opencv::core::CV_32FC1)?;
+    }
+    for i in 0..rows {
+        for j in 0..cols {
+            *out_divergence.at_2d_mut::<f32>(i, j)? =
+                gradient(i, j, 0, 1)? - gradient(i, j - 1, 0, 1)? +
+                gradient(i, j, 1, 0)? - gradient(i - 1, j, 1, 0)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Solve the Poisson equation laplacian(x) = divergence with Neumann
+// boundaries. The DCT diagonalizes the 5 point laplacian with mirrored
+// borders, so the solution is a division in the DCT domain.
+fn solve_poisson(divergence: &Mat,
+                 out_solution: &mut Mat) -> Result<(), HdrError> {
+    let rows = divergence.rows();
+    let cols = divergence.cols();
+
+    let mut coefficients: Mat = Mat::default()?;
+    opencv::core::dct(divergence, &mut coefficients, 0)?;
+    for i in 0..rows {
+        for j in 0..cols {
+            let eigenvalue: f32 = 2.0 * (std::f32::consts::PI * i as f32 / rows as f32).cos() +
+                                  2.0 * (std::f32::consts::PI * j as f32 / cols as f32).cos() - 4.0;
+            let coefficient = coefficients.at_2d_mut::<f32>(i, j)?;
+            // The mean is free, set it to 0.
+            *coefficient = if i == 0 && j == 0 { 0.0 } else { *coefficient / eigenvalue };
+        }
+    }
+    opencv::core::idct(&coefficients, out_solution, 0)?;
+
+    Ok(())
+}
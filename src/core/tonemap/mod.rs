@@ -0,0 +1,11 @@
+/* Copyright 2020 Yuchen Wong */
+
+// Tone-mapping operators turning radiance maps into LDR images.
+
+pub mod display_adaptive_tone_mapping;
+pub mod drago_logarithmic_tone_mapping;
+pub mod durand_bilateral_tone_mapping;
+pub mod fattal_gradient_tone_mapping;
+pub mod photographic_global_tone_mapping;
+pub mod photographic_local_tone_mapping;
+pub mod tone_mapping_utils;
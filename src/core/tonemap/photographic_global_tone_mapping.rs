@@ -0,0 +1,113 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat};
+use opencv::prelude::MatTrait;
+
+use crate::base::math_utils;
+use crate::base::opencv_utils::check_mat_type;
+use crate::core::tonemap::tone_mapping_utils::{compute_l_w, compute_log_average, map_luminance};
+use crate::error::HdrError;
+
+// Fraction of the darkest and brightest pixels ignored as outliers when
+// the parameters are estimated.
+const OUTLIER_RATIO: f32 = 0.01;
+
+pub fn map(src: &Mat,
+           a: f32,
+           l_white: f32,
+           out_ldr: &mut Mat) -> Result<(), HdrError> {
+    log::trace!("Starting tone mapping: PhotoGraphics Global.");
+    check_mat_type(src, opencv::core::CV_32FC3)?;
+
+    let mut l_w: Mat = Mat::default()?;
+    let mut radiance_map: Mat = Mat::default()?;
+    compute_radiance(src, a, l_white, &mut l_w, &mut radiance_map)?;
+
+    map_luminance(src, &l_w, &radiance_map, out_ldr)?;
+
+    log::trace!("Tone mapping finished: PhotoGraphics Global.");
+
+    Ok(())
+}
+
+// Tone map with the key and white point estimated by estimate_parameters,
+// so that batches of images are exposed consistently without tuning.
+pub fn map_auto(src: &Mat,
+                out_ldr: &mut Mat) -> Result<(), HdrError> {
+    check_mat_type(src, opencv::core::CV_32FC3)?;
+    let (a, l_white) = estimate_parameters(src)?;
+    map(src, a, l_white, out_ldr)
+}
+
+// Reinhard's parameter estimation for photographic tone reproduction.
+// The key is 0.18 * 4^((2 * log2(l_avg) - log2(l_min) - log2(l_max)) / (log2(l_max) - log2(l_min)))
+// so that low key scenes stay darker and high key ones brighter, and the
+// white point is 1.5 * 2^(log2(l_max) - log2(l_min) - 5), which grows with
+// the dynamic range in stops. l_min and l_max are the luminances of the
+// darkest and brightest percent of the pixels, l_avg the log-average.
+// Returns (a, l_white), where a is also the alpha of the local operator.
+pub fn estimate_parameters(src: &Mat) -> Result<(f32, f32), HdrError> {
+    check_mat_type(src, opencv::core::CV_32FC3)?;
+
+    let mut l_w: Mat = Mat::default()?;
+    compute_l_w(src, &mut l_w)?;
+
+    // Negative luminances are clamped and non-finite ones dropped, so that
+    // a few broken pixels can not poison the statistics.
+    let mut log2_l_w: Vec<f32> = Vec::with_capacity((l_w.rows() * l_w.cols()) as usize);
+    for i in 0..l_w.rows() {
+        for j in 0..l_w.cols() {
+            let value: f32 = (l_w.at_2d::<f32>(i, j)?.max(0.0) + math_utils::EPSILON as f32).log2();
+            if value.is_finite() {
+                log2_l_w.push(value);
+            }
+        }
+    }
+    if log2_l_w.is_empty() {
+        return Err(HdrError::InvalidArgument(String::from("No finite luminance to estimate the parameters from.")));
+    }
+    let log2_l_avg: f32 = log2_l_w.iter().sum::<f32>() / log2_l_w.len() as f32;
+    log2_l_w.sort_by(|a, b| a.total_cmp(b));
+    let clip_count: usize = (log2_l_w.len() as f32 * OUTLIER_RATIO) as usize;
+    let log2_l_min: f32 = log2_l_w[clip_count];
+    let log2_l_max: f32 = log2_l_w[log2_l_w.len() - 1 - clip_count];
+
+    let a: f32 = if log2_l_max > log2_l_min {
+        0.18 * 4.0f32.powf((2.0 * log2_l_avg - log2_l_min - log2_l_max) / (log2_l_max - log2_l_min))
+    } else {
+        0.18
+    };
+    let l_white: f32 = 1.5 * 2.0f32.powf(log2_l_max - log2_l_min - 5.0);
+    log::info!("Estimated key {} and white point {} from {} stops.", a, l_white, log2_l_max - log2_l_min);
+
+    Ok((a, l_white))
+}
+
+fn compute_radiance(src: &Mat,
+                    a: f32,
+                    l_white: f32,
+                    out_l_w: &mut Mat,
+                    out_radiance_map: &mut Mat) -> Result<(), HdrError> {
+    compute_l_w(src, out_l_w)?;
+
+    let l_w_hat: f32 = compute_log_average(out_l_w)?;
+    log::trace!("{}", l_w_hat);
+
+    let rows: i32 = src.rows();
+    let cols: i32 = src.cols();
+    unsafe {
+        out_radiance_map.create_rows_cols(rows, cols, opencv::core::CV_32FC1)?;
+    }
+
+    let l_white_inv_2: f32 = 1.0 / (l_white * l_white);
+    for i in 0..rows {
+        for j in 0..cols {
+            let cur_l_w: f32 = *out_l_w.at_2d::<f32>(i, j)?;
+            let cur_l: f32 = (cur_l_w / l_w_hat) * a;
+            let cur_l_d: f32 = (cur_l * (1.0 + cur_l * l_white_inv_2)) / (1.0 + cur_l);
+            *out_radiance_map.at_2d_mut::<f32>(i, j)? = cur_l_d;
+        }
+    }
+
+    Ok(())
+}
@@ -1,69 +1,64 @@
 /* Copyright 2020 Yuchen Wong */
 
-use opencv::core::{Mat, Scalar_, Size_, Vec3f};
+use opencv::core::{Mat, Scalar_, Size_};
 use opencv::prelude::{MatTrait, Vector};
 use opencv::types::{VectorOfMat};
-use std::error::Error;
 
-#[path = "../base/math_utils.rs"] mod math_utils;
+use crate::base::opencv_utils::check_mat_type;
+use crate::core::tonemap::photographic_global_tone_mapping::estimate_parameters;
+use crate::core::tonemap::tone_mapping_utils::{compute_l_w, compute_log_average, map_luminance};
+use crate::error::HdrError;
 
 pub fn map(src: &Mat,
            alpha: f32,
            phi: f32,
            epsilon: f32,
            max_kernel_size: i32,
-           out_ldr: &mut Mat) -> Result<(), Box<dyn Error>> {
+           out_ldr: &mut Mat) -> Result<(), HdrError> {
     log::trace!("Starting tone mapping: PhotoGraphics Local.");
+    check_mat_type(src, opencv::core::CV_32FC3)?;
+    if max_kernel_size < 3 {
+        return Err(HdrError::InvalidArgument(
+            format!("Max kernel size must be at least 3, got {}.", max_kernel_size)));
+    }
 
     let mut l_d: Mat = Mat::default()?;
     let mut l_w: Mat = Mat::default()?;
-    compute_radiance(src, alpha, phi, epsilon, max_kernel_size, &mut l_w, &mut l_d).unwrap();
-
-    let mut tmp_ldr: Mat = src.clone()?;
-    let mut tmp_ldr_array: VectorOfMat = VectorOfMat::new();
-    let mut out_ldr_array: VectorOfMat = VectorOfMat::new();
-    opencv::core::split(&tmp_ldr, &mut tmp_ldr_array).unwrap();
-    for i in 0..3 {
-        let cur_mat: Mat = tmp_ldr_array.get(i).unwrap();
-        let mut tmp_mat: Mat = Mat::default()?;
-        let mut out_mat: Mat = Mat::default()?;
-        opencv::core::divide2(&cur_mat, &l_w, &mut tmp_mat, 1.0, opencv::core::CV_32FC1).unwrap();
-        opencv::core::multiply(&tmp_mat, &l_d, &mut out_mat, 1.0, opencv::core::CV_32FC1).unwrap();
-        out_ldr_array.push(out_mat);
-    }
-    opencv::core::merge(&out_ldr_array, &mut tmp_ldr).unwrap();
+    compute_radiance(src, alpha, phi, epsilon, max_kernel_size, &mut l_w, &mut l_d)?;
 
-    let mut ldr_uncropped: Mat = Mat::default()?;
-    opencv::core::multiply(&tmp_ldr, &Scalar_::all(255.0), &mut ldr_uncropped, 1.0, -1).unwrap();
-    ldr_uncropped.convert_to(out_ldr, opencv::core::CV_8UC3, 1.0, 0.0).unwrap();
+    map_luminance(src, &l_w, &l_d, out_ldr)?;
 
     log::trace!("Tone mapping finished: PhotoGraphics Global.");
     Ok(())
 }
 
+// Tone map with alpha set to the key estimated by
+// photographic_global_tone_mapping::estimate_parameters.
+pub fn map_auto(src: &Mat,
+                phi: f32,
+                epsilon: f32,
+                max_kernel_size: i32,
+                out_ldr: &mut Mat) -> Result<(), HdrError> {
+    let (alpha, _l_white) = estimate_parameters(src)?;
+    map(src, alpha, phi, epsilon, max_kernel_size, out_ldr)
+}
+
 fn compute_radiance(src: &Mat,
                     alpha: f32,
                     phi: f32,
                     epsilon: f32,
                     max_kernel_size: i32,
                     out_l_w: &mut Mat,
-                    out_radiance_map: &mut Mat) -> Result<(), Box<dyn Error>> {
-    compute_l_w(src, out_l_w).unwrap();
+                    out_radiance_map: &mut Mat) -> Result<(), HdrError> {
+    compute_l_w(src, out_l_w)?;
 
-    let mut l_w_log = Mat::default()?;
-    let mut l_w_tmp: Mat = Mat::default()?;
-    opencv::core::add(out_l_w, &Scalar_::all(0.0001), 
-                      &mut l_w_tmp, &opencv::core::no_array()?, opencv::core::CV_32FC1).unwrap();
-    opencv::core::log(&l_w_tmp, &mut l_w_log).unwrap();
-    l_w_tmp.release()?;
-
-    let l_w_hat: f32 = opencv::core::mean(&l_w_log, &opencv::core::no_array()?).unwrap()[0].exp() as f32;
+    let l_w_hat: f32 = compute_log_average(out_l_w)?;
     log::trace!("{}", l_w_hat);
 
     let rows: i32 = src.rows();
     let cols: i32 = src.cols();
     unsafe {
-        out_radiance_map.create_rows_cols(rows, cols, opencv::core::CV_32FC1).unwrap();
+        out_radiance_map.create_rows_cols(rows, cols, opencv::core::CV_32FC1)?;
     }
 
     let mut l_m: Mat = Mat::default()?;
@@ -83,15 +78,15 @@ fn compute_radiance(src: &Mat,
 
     let mut l_d_down = Mat::default()?;
     unsafe {
-        l_d_down.create_rows_cols(rows, cols, opencv::core::CV_32FC1).unwrap();
+        l_d_down.create_rows_cols(rows, cols, opencv::core::CV_32FC1)?;
     }
 
     log::info!("Starting doing local ops.");
     let gaussian_num = gaussian_filters.len();
     for i in 0..(gaussian_num-1) {
         let s: f32 = 2.0 * (i as f32) + 1.0;
-        let cur_gaussian: Mat = gaussian_filters.get(i).unwrap();
-        let next_gaussian: Mat = gaussian_filters.get(i+1).unwrap();
+        let cur_gaussian: Mat = gaussian_filters.get(i)?;
+        let next_gaussian: Mat = gaussian_filters.get(i+1)?;
 
         let mut down: Mat = Mat::default()?;
         opencv::core::add(&cur_gaussian, &Scalar_::all((phi.exp2()*alpha/s.powi(2)) as f64), 
@@ -103,10 +98,10 @@ fn compute_radiance(src: &Mat,
 
         for row in 0..rows {
             for col in 0..cols {
-                let cur_v: f32 = *v.at_2d::<f32>(row, col).unwrap();
+                let cur_v: f32 = *v.at_2d::<f32>(row, col)?;
                 if cur_v.abs() < epsilon {
-                    *l_d_down.at_2d_mut::<f32>(row, col).unwrap() = 1.0 +
-                        *cur_gaussian.at_2d::<f32>(row, col).unwrap();
+                    *l_d_down.at_2d_mut::<f32>(row, col)? = 1.0 +
+                        *cur_gaussian.at_2d::<f32>(row, col)?;
                 }
             }
         }
@@ -116,25 +111,3 @@ fn compute_radiance(src: &Mat,
 
     Ok(())
 }
-
-fn compute_l_w(src: &Mat,
-               dst: &mut Mat) -> Result<(), Box<dyn Error>> {
-    let rows = src.rows();
-    let cols = src.cols();
-
-    unsafe {
-        dst.create_rows_cols(rows, cols, opencv::core::CV_32FC1)?;
-    }
-
-    for i in 0..rows {
-        for j in 0..cols {
-            let pixel_value: Vec3f = *src.at_2d::<Vec3f>(i, j).unwrap();
-            let pixel_b = pixel_value[0] as f32;
-            let pixel_g = pixel_value[1] as f32;
-            let pixel_r = pixel_value[2] as f32;
-            *dst.at_2d_mut::<f32>(i, j).unwrap() = 0.06 * pixel_b + 0.67 * pixel_g + 0.27 * pixel_r;
-        }
-    }
-
-    Ok(())
-}
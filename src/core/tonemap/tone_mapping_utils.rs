@@ -0,0 +1,125 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat, Scalar_, Vec3f};
+use opencv::prelude::{MatTrait, Vector};
+use opencv::types::{VectorOfMat};
+
+use crate::base::math_utils;
+use crate::error::HdrError;
+
+// This file contains the luminance helpers shared by the tone mapping
+// operators, which all compress the luminance of the radiance map and
+// keep the ratios between its color channels.
+
+// The world luminance of a CV_32FC3 BGR radiance map, as CV_32FC1.
+pub fn compute_l_w(src: &Mat,
+                   dst: &mut Mat) -> Result<(), HdrError> {
+    let rows = src.rows();
+    let cols = src.cols();
+
+    unsafe {
+        dst.create_rows_cols(rows, cols, opencv::core::CV_32FC1)?;
+    }
+
+    for i in 0..rows {
+        for j in 0..cols {
+            let pixel_value: Vec3f = *src.at_2d::<Vec3f>(i, j)?;
+            let pixel_b = pixel_value[0] as f32;
+            let pixel_g = pixel_value[1] as f32;
+            let pixel_r = pixel_value[2] as f32;
+            *dst.at_2d_mut::<f32>(i, j)? = 0.06 * pixel_b + 0.67 * pixel_g + 0.27 * pixel_r;
+        }
+    }
+
+    Ok(())
+}
+
+// The log-average luminance of Reinhard et al., exp(mean(log(delta + l_w))),
+// which approximates the key of the scene. Negative luminances are clamped
+// and non-finite ones skipped, so that a few broken pixels can not poison
+// the average.
+pub fn compute_log_average(l_w: &Mat) -> Result<f32, HdrError> {
+    let mut sum: f64 = 0.0;
+    let mut count: usize = 0;
+    for i in 0..l_w.rows() {
+        for j in 0..l_w.cols() {
+            let value: f64 = (l_w.at_2d::<f32>(i, j)?.max(0.0) as f64 + math_utils::EPSILON).ln();
+            if value.is_finite() {
+                sum += value;
+                count += 1;
+            }
+        }
+    }
+    if count == 0 {
+        return Err(HdrError::InvalidArgument(String::from("No finite luminance to average.")));
+    }
+
+    Ok((sum / count as f64).exp() as f32)
+}
+
+// Scale every channel of src by l_d / l_w, the display luminance in [0, 1]
+// over the world luminance, into a CV_8UC3 image.
+pub fn map_luminance(src: &Mat,
+                     l_w: &Mat,
+                     l_d: &Mat,
+                     out_ldr: &mut Mat) -> Result<(), HdrError> {
+    let mut tmp_ldr: Mat = src.clone()?;
+    let mut tmp_ldr_array: VectorOfMat = VectorOfMat::new();
+    let mut out_ldr_array: VectorOfMat = VectorOfMat::new();
+    opencv::core::split(&tmp_ldr, &mut tmp_ldr_array)?;
+    for i in 0..3 {
+        let cur_mat: Mat = tmp_ldr_array.get(i)?;
+        let mut tmp_mat: Mat = Mat::default()?;
+        let mut out_mat: Mat = Mat::default()?;
+        opencv::core::divide2(&cur_mat, l_w, &mut tmp_mat, 1.0, opencv::core::CV_32FC1)?;
+        opencv::core::multiply(&tmp_mat, l_d, &mut out_mat, 1.0, opencv::core::CV_32FC1)?;
+        out_ldr_array.push(out_mat);
+    }
+    opencv::core::merge(&out_ldr_array, &mut tmp_ldr)?;
+
+    let mut ldr_uncropped: Mat = Mat::default()?;
+    opencv::core::multiply(&tmp_ldr, &Scalar_::all(255.0), &mut ldr_uncropped, 1.0, -1)?;
+    ldr_uncropped.convert_to(out_ldr, opencv::core::CV_8UC3, 1.0, 0.0)?;
+
+    Ok(())
+}
+
+// Like map_luminance, with the channels set to (C / l_w)^saturation * l_d
+// as Schlick suggests, so that strongly compressed images are not
+// oversaturated. A saturation of 1 keeps the ratios of map_luminance.
+// l_d is linear, the channels are encoded with 1 / gamma once the color
+// is rebuilt, a gamma of 1 leaving them linear.
+pub fn map_luminance_with_saturation(src: &Mat,
+                                     l_w: &Mat,
+                                     l_d: &Mat,
+                                     saturation: f32,
+                                     gamma: f32,
+                                     out_ldr: &mut Mat) -> Result<(), HdrError> {
+    let rows = src.rows();
+    let cols = src.cols();
+    let mut tmp_ldr: Mat = Mat::default()?;
+    unsafe {
+        tmp_ldr.create_rows_cols(rows, cols, opencv::core::CV_32FC3)?;
+    }
+
+    for i in 0..rows {
+        for j in 0..cols {
+            let pixel_value: Vec3f = *src.at_2d::<Vec3f>(i, j)?;
+            let cur_l_w: f32 = *l_w.at_2d::<f32>(i, j)?;
+            let cur_l_d: f32 = *l_d.at_2d::<f32>(i, j)?;
+            let out_pixel = tmp_ldr.at_2d_mut::<Vec3f>(i, j)?;
+            for c in 0..3 {
+                let value: f32 = if cur_l_w > 0.0 {
+                    (pixel_value[c].max(0.0) / cur_l_w).powf(saturation) * cur_l_d
+                } else {
+                    cur_l_d
+                };
+                out_pixel[c] = value.max(0.0).min(1.0).powf(1.0 / gamma);
+            }
+        }
+    }
+
+    tmp_ldr.convert_to(out_ldr, opencv::core::CV_8UC3, 255.0, 0.0)?;
+
+    Ok(())
+}
@@ -0,0 +1,85 @@
+/* Copyright 2020 Yuchen Wong */
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// The error type returned by all the public functions of hdr-rust.
+#[derive(Debug)]
+pub enum HdrError {
+    // Images which should share the same size do not.
+    // Sizes are given as (rows, cols).
+    MismatchedImageSize { expected: (i32, i32), actual: (i32, i32) },
+    // A Mat has a type (e.g. CV_8UC3) the algorithm does not accept.
+    InvalidMatType { expected: i32, actual: i32 },
+    // No image is given to an algorithm working on a set of images.
+    EmptyImages,
+    // No exposure time is given to an algorithm merging a bracket.
+    EmptyExposures,
+    // The number of exposure times differs from the number of images.
+    MismatchedExposureCount { images: usize, exposures: usize },
+    // Not enough feature matches to estimate an alignment.
+    TooFewMatches { required: usize, found: usize },
+    // A parameter is out of its valid range.
+    InvalidArgument(String),
+    // Metadata needed by an algorithm, e.g. EXIF exposure time, is missing.
+    MissingMetadata(String),
+    // A file does not follow the format it is read as.
+    InvalidFormat(String),
+    // An error raised when reading or writing a file.
+    Io(io::Error),
+    // An error raised by opencv.
+    OpenCV(opencv::Error),
+}
+
+impl fmt::Display for HdrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HdrError::MismatchedImageSize { expected, actual } =>
+                write!(f, "Mismatched image size: expected {}x{}, got {}x{}.",
+                       expected.0, expected.1, actual.0, actual.1),
+            HdrError::InvalidMatType { expected, actual } =>
+                write!(f, "Invalid Mat type: expected {}, got {}.", expected, actual),
+            HdrError::EmptyImages =>
+                write!(f, "No input image is given."),
+            HdrError::EmptyExposures =>
+                write!(f, "No exposure time is given."),
+            HdrError::MismatchedExposureCount { images, exposures } =>
+                write!(f, "Got {} exposure times for {} images.", exposures, images),
+            HdrError::TooFewMatches { required, found } =>
+                write!(f, "Too few feature matches: required {}, found {}.", required, found),
+            HdrError::InvalidArgument(message) =>
+                write!(f, "Invalid argument: {}", message),
+            HdrError::MissingMetadata(message) =>
+                write!(f, "Missing metadata: {}", message),
+            HdrError::InvalidFormat(message) =>
+                write!(f, "Invalid format: {}", message),
+            HdrError::Io(e) =>
+                write!(f, "IO error: {}", e),
+            HdrError::OpenCV(e) =>
+                write!(f, "OpenCV error: {}", e),
+        }
+    }
+}
+
+impl Error for HdrError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HdrError::Io(e) => Some(e),
+            HdrError::OpenCV(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<opencv::Error> for HdrError {
+    fn from(e: opencv::Error) -> Self {
+        HdrError::OpenCV(e)
+    }
+}
+
+impl From<io::Error> for HdrError {
+    fn from(e: io::Error) -> Self {
+        HdrError::Io(e)
+    }
+}
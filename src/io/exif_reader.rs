@@ -0,0 +1,373 @@
+/* Copyright 2020 Yuchen Wong */
+
+use std::fs;
+
+use crate::error::HdrError;
+
+// This file contains a minimal EXIF reader, only looking for the tags
+// needed to compute the exposure of a bracket.
+// Both JPEG (EXIF in the APP1 segment) and TIFF based files are supported.
+
+const TAG_EXPOSURE_TIME: u16 = 0x829a;
+const TAG_F_NUMBER: u16 = 0x829d;
+const TAG_ISO_SPEED: u16 = 0x8827;
+const TAG_EXIF_IFD_POINTER: u16 = 0x8769;
+
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+const TYPE_SRATIONAL: u16 = 10;
+
+// Exposure related metadata of an image, None if the tag is missing.
+#[derive(Clone, Debug, Default)]
+pub struct ExposureInfo {
+    // Exposure time in seconds.
+    pub exposure_time: Option<f32>,
+    pub f_number: Option<f32>,
+    pub iso: Option<f32>,
+}
+
+// Read the exposure metadata of a JPEG or TIFF file.
+pub fn read_exposure_info(path: &str) -> Result<ExposureInfo, HdrError> {
+    let data: Vec<u8> = fs::read(path)?;
+    let tiff: &[u8] = match find_tiff_data(&data) {
+        Some(tiff) => tiff,
+        None => return Ok(ExposureInfo::default()),
+    };
+
+    let mut info = ExposureInfo::default();
+    let reader = TiffReader::new(tiff)
+        .ok_or_else(|| HdrError::InvalidFormat(format!("{}: invalid TIFF header.", path)))?;
+    let ifd0_offset = reader.read_u32(4)
+        .ok_or_else(|| HdrError::InvalidFormat(format!("{}: truncated TIFF header.", path)))?;
+
+    // Exposure tags normally live in the EXIF sub IFD, but TIFF based
+    // raw files may also store them in IFD0.
+    let mut exif_ifd_offset: Option<u32> = None;
+    for (tag, typ, count, value_offset) in reader.read_ifd(ifd0_offset as usize) {
+        if tag == TAG_EXIF_IFD_POINTER {
+            exif_ifd_offset = reader.read_value(typ, count, value_offset).map(|v| v as u32);
+        } else {
+            read_exposure_tag(&reader, tag, typ, count, value_offset, &mut info);
+        }
+    }
+    if let Some(offset) = exif_ifd_offset {
+        for (tag, typ, count, value_offset) in reader.read_ifd(offset as usize) {
+            read_exposure_tag(&reader, tag, typ, count, value_offset, &mut info);
+        }
+    }
+
+    log::info!("{}: exposure time {:?}, f-number {:?}, iso {:?}.",
+               path, info.exposure_time, info.f_number, info.iso);
+    Ok(info)
+}
+
+// Compute the relative exposure of every image of a bracket from its
+// EXIF metadata. The exposure time is scaled by the ISO and the inverse
+// square of the f-number relative to the first image, so that brackets
+// varying only the shutter speed get their exposure times back.
+// F-number and ISO are only used when every image has them.
+pub fn read_shutter_speeds(paths: &Vec<&str>) -> Result<Vec<f32>, HdrError> {
+    if paths.len() == 0 {
+        return Err(HdrError::EmptyImages);
+    }
+
+    let mut infos: Vec<ExposureInfo> = Vec::with_capacity(paths.len());
+    for path in paths {
+        let info = read_exposure_info(path)?;
+        if info.exposure_time.is_none() {
+            return Err(HdrError::MissingMetadata(
+                format!("{} has no EXIF exposure time.", path)));
+        }
+        infos.push(info);
+    }
+
+    let use_f_number = infos.iter().all(|info| info.f_number.map_or(false, |v| v > 0.0));
+    let use_iso = infos.iter().all(|info| info.iso.map_or(false, |v| v > 0.0));
+
+    let mut shutter_speeds: Vec<f32> = Vec::with_capacity(infos.len());
+    for info in &infos {
+        let mut shutter_speed = info.exposure_time.unwrap();
+        if use_f_number {
+            let f_number_ratio = infos[0].f_number.unwrap() / info.f_number.unwrap();
+            shutter_speed *= f_number_ratio * f_number_ratio;
+        }
+        if use_iso {
+            shutter_speed *= info.iso.unwrap() / infos[0].iso.unwrap();
+        }
+        shutter_speeds.push(shutter_speed);
+    }
+
+    Ok(shutter_speeds)
+}
+
+fn read_exposure_tag(reader: &TiffReader,
+                     tag: u16,
+                     typ: u16,
+                     count: u32,
+                     value_offset: usize,
+                     info: &mut ExposureInfo) {
+    match tag {
+        TAG_EXPOSURE_TIME => info.exposure_time = reader.read_value(typ, count, value_offset).map(|v| v as f32),
+        TAG_F_NUMBER => info.f_number = reader.read_value(typ, count, value_offset).map(|v| v as f32),
+        TAG_ISO_SPEED => info.iso = reader.read_value(typ, count, value_offset).map(|v| v as f32),
+        _ => {},
+    }
+}
+
+// Locate the TIFF structure holding the EXIF data.
+fn find_tiff_data(data: &[u8]) -> Option<&[u8]> {
+    if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        return Some(data);
+    }
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+
+    // Walk through the JPEG segments until the APP1 EXIF segment.
+    let mut pos: usize = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xff {
+            return None;
+        }
+        let marker = data[pos + 1];
+        // Start of scan, no metadata after this.
+        if marker == 0xda {
+            return None;
+        }
+        let length = ((data[pos + 2] as usize) << 8) | data[pos + 3] as usize;
+        let segment_end = pos + 2 + length;
+        if length < 2 || segment_end > data.len() {
+            return None;
+        }
+        let segment = &data[pos + 4..segment_end];
+        if marker == 0xe1 && segment.starts_with(b"Exif\0\0") {
+            return Some(&segment[6..]);
+        }
+        pos = segment_end;
+    }
+
+    None
+}
+
+struct TiffReader<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> TiffReader<'a> {
+    fn new(data: &'a [u8]) -> Option<TiffReader<'a>> {
+        let little_endian = if data.starts_with(b"II") {
+            true
+        } else if data.starts_with(b"MM") {
+            false
+        } else {
+            return None;
+        };
+
+        Some(TiffReader { data: data, little_endian: little_endian })
+    }
+
+    fn read_u16(&self, pos: usize) -> Option<u16> {
+        let bytes = self.data.get(pos..pos + 2)?;
+        if self.little_endian {
+            Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+        } else {
+            Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+        }
+    }
+
+    fn read_u32(&self, pos: usize) -> Option<u32> {
+        let bytes = self.data.get(pos..pos + 4)?;
+        if self.little_endian {
+            Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        } else {
+            Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        }
+    }
+
+    // Read all entries of an IFD as (tag, type, count, position of value).
+    // The value position is the inline field for values up to 4 bytes.
+    fn read_ifd(&self, offset: usize) -> Vec<(u16, u16, u32, usize)> {
+        let mut entries: Vec<(u16, u16, u32, usize)> = Vec::new();
+        let entry_num = match self.read_u16(offset) {
+            Some(entry_num) => entry_num as usize,
+            None => return entries,
+        };
+
+        for i in 0..entry_num {
+            let entry_pos = offset + 2 + 12 * i;
+            let (tag, typ, count) = match (self.read_u16(entry_pos),
+                                           self.read_u16(entry_pos + 2),
+                                           self.read_u32(entry_pos + 4)) {
+                (Some(tag), Some(typ), Some(count)) => (tag, typ, count),
+                _ => break,
+            };
+            let size = match typ {
+                TYPE_SHORT => 2,
+                TYPE_LONG => 4,
+                TYPE_RATIONAL | TYPE_SRATIONAL => 8,
+                _ => 1,
+            } * count as usize;
+            let value_pos = if size <= 4 {
+                entry_pos + 8
+            } else {
+                match self.read_u32(entry_pos + 8) {
+                    Some(value_offset) => value_offset as usize,
+                    None => break,
+                }
+            };
+            entries.push((tag, typ, count, value_pos));
+        }
+
+        entries
+    }
+
+    // Read the first value of an entry as a number.
+    fn read_value(&self, typ: u16, count: u32, pos: usize) -> Option<f64> {
+        if count == 0 {
+            return None;
+        }
+        match typ {
+            TYPE_SHORT => self.read_u16(pos).map(|v| v as f64),
+            TYPE_LONG => self.read_u32(pos).map(|v| v as f64),
+            TYPE_RATIONAL | TYPE_SRATIONAL => {
+                let numerator = self.read_u32(pos)?;
+                let denominator = self.read_u32(pos + 4)?;
+                if denominator == 0 {
+                    return None;
+                }
+                if typ == TYPE_SRATIONAL {
+                    Some(numerator as i32 as f64 / denominator as i32 as f64)
+                } else {
+                    Some(numerator as f64 / denominator as f64)
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::test_utils::TempFile;
+
+    // A TIFF with the exposure tags in the EXIF sub IFD: the exposure time
+    // and f-number as rationals, the iso as an inline short.
+    fn build_tiff(little_endian: bool,
+                  exposure_time: (u32, u32),
+                  f_number: (u32, u32),
+                  iso: u16) -> Vec<u8> {
+        let u16_bytes = |v: u16| if little_endian { v.to_le_bytes() } else { v.to_be_bytes() };
+        let u32_bytes = |v: u32| if little_endian { v.to_le_bytes() } else { v.to_be_bytes() };
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(if little_endian { b"II*\0" } else { b"MM\0*" });
+        data.extend_from_slice(&u32_bytes(8));
+
+        // IFD0 at 8, with the EXIF IFD pointer only.
+        data.extend_from_slice(&u16_bytes(1));
+        data.extend_from_slice(&u16_bytes(TAG_EXIF_IFD_POINTER));
+        data.extend_from_slice(&u16_bytes(TYPE_LONG));
+        data.extend_from_slice(&u32_bytes(1));
+        data.extend_from_slice(&u32_bytes(26));
+        data.extend_from_slice(&u32_bytes(0));
+
+        // EXIF IFD at 26, the rationals follow it at 68.
+        data.extend_from_slice(&u16_bytes(3));
+        data.extend_from_slice(&u16_bytes(TAG_EXPOSURE_TIME));
+        data.extend_from_slice(&u16_bytes(TYPE_RATIONAL));
+        data.extend_from_slice(&u32_bytes(1));
+        data.extend_from_slice(&u32_bytes(68));
+        data.extend_from_slice(&u16_bytes(TAG_F_NUMBER));
+        data.extend_from_slice(&u16_bytes(TYPE_RATIONAL));
+        data.extend_from_slice(&u32_bytes(1));
+        data.extend_from_slice(&u32_bytes(76));
+        data.extend_from_slice(&u16_bytes(TAG_ISO_SPEED));
+        data.extend_from_slice(&u16_bytes(TYPE_SHORT));
+        data.extend_from_slice(&u32_bytes(1));
+        data.extend_from_slice(&u16_bytes(iso));
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&u32_bytes(0));
+
+        data.extend_from_slice(&u32_bytes(exposure_time.0));
+        data.extend_from_slice(&u32_bytes(exposure_time.1));
+        data.extend_from_slice(&u32_bytes(f_number.0));
+        data.extend_from_slice(&u32_bytes(f_number.1));
+        data
+    }
+
+    fn wrap_in_jpeg(tiff: &[u8]) -> Vec<u8> {
+        let length = 2 + 6 + tiff.len();
+        let mut data: Vec<u8> = vec![0xff, 0xd8, 0xff, 0xe1, (length >> 8) as u8, (length & 0xff) as u8];
+        data.extend_from_slice(b"Exif\0\0");
+        data.extend_from_slice(tiff);
+        data.extend_from_slice(&[0xff, 0xda, 0, 2]);
+        data
+    }
+
+    #[test]
+    fn reads_little_and_big_endian_tiff() {
+        for &little_endian in &[true, false] {
+            let name = if little_endian { "le.tif" } else { "be.tif" };
+            let file = TempFile::with_content(name, &build_tiff(little_endian, (1, 125), (28, 10), 200));
+            let info = read_exposure_info(file.path()).unwrap();
+            assert_eq!(info.exposure_time, Some(0.008));
+            assert_eq!(info.f_number, Some(2.8));
+            assert_eq!(info.iso, Some(200.0));
+        }
+    }
+
+    #[test]
+    fn reads_jpeg_app1_segment() {
+        let file = TempFile::with_content("app1.jpg", &wrap_in_jpeg(&build_tiff(true, (1, 4), (8, 1), 100)));
+        let info = read_exposure_info(file.path()).unwrap();
+        assert_eq!(info.exposure_time, Some(0.25));
+        assert_eq!(info.f_number, Some(8.0));
+        assert_eq!(info.iso, Some(100.0));
+    }
+
+    #[test]
+    fn scales_shutter_speeds_by_f_number_and_iso() {
+        let first_file = TempFile::with_content("bracket0.tif", &build_tiff(true, (1, 100), (4, 1), 100));
+        let second_file = TempFile::with_content("bracket1.tif", &build_tiff(true, (1, 100), (8, 1), 400));
+        let shutter_speeds = read_shutter_speeds(&vec![first_file.path(), second_file.path()]).unwrap();
+        assert_eq!(shutter_speeds.len(), 2);
+        assert!((shutter_speeds[0] - 0.01).abs() < 1e-6);
+        // Two stops less light from the aperture, two more from the iso.
+        assert!((shutter_speeds[1] - 0.01).abs() < 1e-6);
+    }
+
+    #[test]
+    fn missing_metadata_is_not_an_error() {
+        let file = TempFile::with_content("no_exif.jpg", &[0xff, 0xd8, 0xff, 0xda, 0, 2]);
+        let info = read_exposure_info(file.path()).unwrap();
+        assert!(info.exposure_time.is_none() && info.f_number.is_none() && info.iso.is_none());
+
+        match read_shutter_speeds(&vec![file.path()]) {
+            Err(HdrError::MissingMetadata(_)) => {},
+            other => panic!("expected MissingMetadata, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_data() {
+        // An EXIF segment without a TIFF header.
+        let file = TempFile::with_content("bad_header.jpg", &wrap_in_jpeg(b"XX\0\0\0\0\0\0"));
+        match read_exposure_info(file.path()) {
+            Err(HdrError::InvalidFormat(_)) => {},
+            other => panic!("expected InvalidFormat, got {:?}", other),
+        }
+
+        // Offsets past the end of the data and a zero denominator are ignored.
+        let mut tiff = build_tiff(true, (1, 0), (28, 10), 200);
+        tiff.truncate(76);
+        let file = TempFile::with_content("truncated.tif", &tiff);
+        let info = read_exposure_info(file.path()).unwrap();
+        assert!(info.exposure_time.is_none() && info.f_number.is_none());
+        assert_eq!(info.iso, Some(200.0));
+
+        // A JPEG segment longer than the file.
+        assert!(find_tiff_data(&[0xff, 0xd8, 0xff, 0xe1, 0x10, 0x00, b'E']).is_none());
+    }
+}
@@ -0,0 +1,158 @@
+/* Copyright 2020 Yuchen Wong */
+
+use exr::meta::attribute::AttributeValue;
+use exr::prelude::{f16, Blocks, Compression, Encoding, Image, Layer, LayerAttributes, LineOrder,
+                   SpecificChannels, Text, WritableImage};
+use opencv::core::{Mat, Vec3f};
+use opencv::prelude::MatTrait;
+
+use crate::error::HdrError;
+
+// This file contains an OpenEXR writer with more control than
+// opencv_utils::save_exr_with_default, which always writes full float
+// pixels with the default compression and no metadata.
+
+// Storage type of the pixels in the file. Half floats take half the
+// space and keep about 3 significant digits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExrPixelType {
+    Half,
+    Float,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExrCompression {
+    None,
+    Rle,
+    // Deflate on blocks of 16 scanlines.
+    Zip,
+    // Wavelet and huffman coding, best for noisy photographs.
+    Piz,
+}
+
+// Value of a custom header attribute.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExrAttribute {
+    Text(String),
+    Float(f32),
+    Int(i32),
+}
+
+#[derive(Clone, Debug)]
+pub struct ExrOptions {
+    pub pixel_type: ExrPixelType,
+    pub compression: ExrCompression,
+    // Custom attributes added to the header as (name, value).
+    pub attributes: Vec<(String, ExrAttribute)>,
+}
+
+impl Default for ExrOptions {
+    fn default() -> Self {
+        ExrOptions {
+            pixel_type: ExrPixelType::Float,
+            compression: ExrCompression::Zip,
+            attributes: Vec::new(),
+        }
+    }
+}
+
+// Save a CV_32FC3 BGR image as an RGB OpenEXR file,
+// or a CV_32FC1 image, e.g. a luminance map, as a Y file.
+pub fn save_exr(path: &str,
+                image: &Mat,
+                options: &ExrOptions) -> Result<(), HdrError> {
+    log::trace!("Writing OpenEXR {}: {:?}, {:?}.", path, options.pixel_type, options.compression);
+
+    let typ = image.typ()?;
+    let channels: usize = match typ {
+        opencv::core::CV_32FC1 => 1,
+        opencv::core::CV_32FC3 => 3,
+        _ => return Err(HdrError::InvalidMatType { expected: opencv::core::CV_32FC3, actual: typ }),
+    };
+
+    // Collect the pixels in RGB order, as the writer asks them pixel by pixel.
+    let rows = image.rows() as usize;
+    let cols = image.cols() as usize;
+    let mut pixels: Vec<f32> = Vec::with_capacity(rows * cols * channels);
+    for i in 0..rows as i32 {
+        for j in 0..cols as i32 {
+            if channels == 3 {
+                let pixel: Vec3f = *image.at_2d::<Vec3f>(i, j)?;
+                pixels.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            } else {
+                pixels.push(*image.at_2d::<f32>(i, j)?);
+            }
+        }
+    }
+
+    write_exr(path, rows, cols, channels, &pixels, options)
+}
+
+fn write_exr(path: &str,
+             rows: usize,
+             cols: usize,
+             channels: usize,
+             pixels: &[f32],
+             options: &ExrOptions) -> Result<(), HdrError> {
+    let mut layer_attributes = LayerAttributes::default();
+    layer_attributes.software_name = Some(Text::from("hdr-rust"));
+    for (name, value) in &options.attributes {
+        let name = to_text(name)?;
+        let value = match value {
+            ExrAttribute::Text(text) => AttributeValue::Text(to_text(text)?),
+            ExrAttribute::Float(v) => AttributeValue::F32(*v),
+            ExrAttribute::Int(v) => AttributeValue::I32(*v),
+        };
+        layer_attributes.other.insert(name, value);
+    }
+
+    let encoding = Encoding {
+        compression: match options.compression {
+            ExrCompression::None => Compression::Uncompressed,
+            ExrCompression::Rle => Compression::RLE,
+            ExrCompression::Zip => Compression::ZIP16,
+            ExrCompression::Piz => Compression::PIZ,
+        },
+        blocks: Blocks::ScanLines,
+        line_order: LineOrder::Increasing,
+    };
+
+    let size = (cols, rows);
+    let rgb = |x: usize, y: usize| {
+        let index = (y * cols + x) * 3;
+        (pixels[index], pixels[index + 1], pixels[index + 2])
+    };
+    let result = match (channels, options.pixel_type) {
+        (3, ExrPixelType::Float) => Image::from_layer(Layer::new(
+            size, layer_attributes, encoding,
+            SpecificChannels::rgb(|position: exr::prelude::Vec2<usize>| rgb(position.x(), position.y()))))
+            .write().to_file(path),
+        (3, ExrPixelType::Half) => Image::from_layer(Layer::new(
+            size, layer_attributes, encoding,
+            SpecificChannels::rgb(|position: exr::prelude::Vec2<usize>| {
+                let (r, g, b) = rgb(position.x(), position.y());
+                (f16::from_f32(r), f16::from_f32(g), f16::from_f32(b))
+            })))
+            .write().to_file(path),
+        (_, ExrPixelType::Float) => Image::from_layer(Layer::new(
+            size, layer_attributes, encoding,
+            SpecificChannels::build().with_channel("Y").with_pixel_fn(
+                |position: exr::prelude::Vec2<usize>| (pixels[position.y() * cols + position.x()],))))
+            .write().to_file(path),
+        (_, ExrPixelType::Half) => Image::from_layer(Layer::new(
+            size, layer_attributes, encoding,
+            SpecificChannels::build().with_channel("Y").with_pixel_fn(
+                |position: exr::prelude::Vec2<usize>| (f16::from_f32(pixels[position.y() * cols + position.x()]),))))
+            .write().to_file(path),
+    };
+
+    result.map_err(|e| match e {
+        exr::error::Error::Io(e) => HdrError::Io(e),
+        e => HdrError::InvalidFormat(format!("{}: {}", path, e)),
+    })
+}
+
+fn to_text(value: &str) -> Result<Text, HdrError> {
+    Text::new_or_none(value).ok_or_else(|| HdrError::InvalidArgument(
+        format!("EXR attribute {:?} is not a latin-1 text.", value)))
+}
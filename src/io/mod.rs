@@ -0,0 +1,9 @@
+/* Copyright 2020 Yuchen Wong */
+
+// Readers and writers of image files and their metadata.
+
+pub mod exif_reader;
+pub mod exr_writer;
+pub mod radiance_hdr;
+pub mod radiance_map_reader;
+pub mod raw_reader;
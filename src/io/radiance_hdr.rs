@@ -0,0 +1,419 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat, Vec3f};
+use opencv::prelude::MatTrait;
+use std::fs;
+
+use crate::base::opencv_utils::check_mat_type;
+use crate::error::HdrError;
+
+// This file contains a reader and a writer of Radiance RGBE (.hdr) files.
+// Pixels are stored as a shared exponent and three 8-bit mantissas,
+// and scanlines are run-length encoded channel by channel.
+// Only the standard "-Y height +X width" orientation is supported.
+
+// Scanlines outside of this range can not be run-length encoded.
+const MIN_RLE_WIDTH: usize = 8;
+const MAX_RLE_WIDTH: usize = 0x7fff;
+// Shorter runs are cheaper to store as literals.
+const MIN_RUN_LENGTH: usize = 4;
+
+// Read a Radiance .hdr file into a CV_32FC3 BGR radiance map.
+// Pixels are divided by the EXPOSURE of the header so that they
+// keep the radiance of the original image.
+pub fn read_hdr(path: &str,
+                out_hdri: &mut Mat) -> Result<(), HdrError> {
+    log::trace!("Reading radiance map {}.", path);
+
+    let data: Vec<u8> = fs::read(path)?;
+    let mut pos: usize = 0;
+    let (width, height, exposure) = read_header(&data, &mut pos)
+        .map_err(|message| HdrError::InvalidFormat(format!("{}: {}", path, message)))?;
+
+    unsafe {
+        out_hdri.create_rows_cols(height as i32, width as i32, opencv::core::CV_32FC3)?;
+    }
+
+    let mut scanline: Vec<[u8; 4]> = Vec::with_capacity(width);
+    for row in 0..height {
+        decode_scanline(&data, &mut pos, width, &mut scanline)
+            .map_err(|message| HdrError::InvalidFormat(format!("{}: scanline {}: {}", path, row, message)))?;
+        for col in 0..width {
+            let (r, g, b) = rgbe_to_float(scanline[col]);
+            let pixel = out_hdri.at_2d_mut::<Vec3f>(row as i32, col as i32)?;
+            pixel[0] = b / exposure;
+            pixel[1] = g / exposure;
+            pixel[2] = r / exposure;
+        }
+    }
+
+    Ok(())
+}
+
+// Write a CV_32FC3 BGR radiance map as a run-length encoded Radiance .hdr file.
+pub fn write_hdr(path: &str,
+                 hdri: &Mat) -> Result<(), HdrError> {
+    log::trace!("Writing radiance map {}.", path);
+
+    check_mat_type(hdri, opencv::core::CV_32FC3)?;
+    let rows = hdri.rows();
+    let cols = hdri.cols();
+
+    let mut data: Vec<u8> = Vec::new();
+    data.extend_from_slice(b"#?RADIANCE\n");
+    data.extend_from_slice(b"# Written by hdr-rust\n");
+    data.extend_from_slice(b"FORMAT=32-bit_rle_rgbe\n\n");
+    data.extend_from_slice(format!("-Y {} +X {}\n", rows, cols).as_bytes());
+
+    let mut scanline: Vec<[u8; 4]> = Vec::with_capacity(cols as usize);
+    for row in 0..rows {
+        scanline.clear();
+        for col in 0..cols {
+            let pixel: Vec3f = *hdri.at_2d::<Vec3f>(row, col)?;
+            scanline.push(float_to_rgbe(pixel[2], pixel[1], pixel[0]));
+        }
+        encode_scanline(&scanline, &mut data);
+    }
+
+    fs::write(path, &data)?;
+    Ok(())
+}
+
+// Parse the header and the resolution line, returning (width, height, exposure).
+fn read_header(data: &[u8],
+               pos: &mut usize) -> Result<(usize, usize, f32), String> {
+    let first_line = read_line(data, pos).ok_or("missing header.")?;
+    if first_line != "#?RADIANCE" && first_line != "#?RGBE" {
+        return Err(format!("unknown magic number {:?}.", first_line));
+    }
+
+    // Header lines end with an empty line.
+    let mut exposure: f32 = 1.0;
+    loop {
+        let line = read_line(data, pos).ok_or("truncated header.")?;
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+            return Err(format!("unsupported {}.", line));
+        }
+        if line.starts_with("EXPOSURE=") {
+            let value: f32 = line["EXPOSURE=".len()..].trim().parse()
+                .map_err(|_| format!("invalid {}.", line))?;
+            // Multiple exposure lines are cumulative.
+            exposure *= value;
+        }
+    }
+    if exposure <= 0.0 {
+        return Err(format!("invalid exposure {}.", exposure));
+    }
+
+    let resolution = read_line(data, pos).ok_or("missing resolution.")?;
+    let fields: Vec<&str> = resolution.split_whitespace().collect();
+    if fields.len() != 4 || fields[0] != "-Y" || fields[2] != "+X" {
+        return Err(format!("unsupported resolution {:?}.", resolution));
+    }
+    let height: usize = fields[1].parse().map_err(|_| format!("invalid height {}.", fields[1]))?;
+    let width: usize = fields[3].parse().map_err(|_| format!("invalid width {}.", fields[3]))?;
+    if width == 0 || height == 0 {
+        return Err(format!("invalid resolution {}x{}.", width, height));
+    }
+
+    Ok((width, height, exposure))
+}
+
+fn read_line(data: &[u8],
+             pos: &mut usize) -> Option<String> {
+    let start = *pos;
+    let length = data.get(start..)?.iter().position(|&c| c == b'\n')?;
+    *pos = start + length + 1;
+    Some(String::from_utf8_lossy(&data[start..start + length]).trim_end().to_string())
+}
+
+fn float_to_rgbe(r: f32,
+                 g: f32,
+                 b: f32) -> [u8; 4] {
+    let v = r.max(g).max(b);
+    if !(v > 1e-32) {
+        return [0, 0, 0, 0];
+    }
+    // Infinite values are clipped to the largest one RGBE can store.
+    if !v.is_finite() {
+        return [255, 255, 255, 255];
+    }
+
+    // v = m * 2^e with m in [0.5, 1), as frexp does.
+    let mut e = v.log2().floor() as i32 + 1;
+    let mut m = v / 2f32.powi(e);
+    if m >= 1.0 {
+        m *= 0.5;
+        e += 1;
+    } else if m < 0.5 {
+        m *= 2.0;
+        e -= 1;
+    }
+    if e + 128 > 255 {
+        return [255, 255, 255, 255];
+    }
+    if e + 128 < 1 {
+        return [0, 0, 0, 0];
+    }
+
+    let scale = m * 256.0 / v;
+    [(r.max(0.0) * scale) as u8, (g.max(0.0) * scale) as u8, (b.max(0.0) * scale) as u8, (e + 128) as u8]
+}
+
+fn rgbe_to_float(rgbe: [u8; 4]) -> (f32, f32, f32) {
+    if rgbe[3] == 0 {
+        return (0.0, 0.0, 0.0);
+    }
+
+    // Mantissas are taken at the middle of their bucket.
+    let f = 2f32.powi(rgbe[3] as i32 - (128 + 8));
+    ((rgbe[0] as f32 + 0.5) * f, (rgbe[1] as f32 + 0.5) * f, (rgbe[2] as f32 + 0.5) * f)
+}
+
+fn decode_scanline(data: &[u8],
+                   pos: &mut usize,
+                   width: usize,
+                   out_scanline: &mut Vec<[u8; 4]>) -> Result<(), String> {
+    out_scanline.clear();
+
+    let head = data.get(*pos..*pos + 4).ok_or("truncated data.")?;
+    let is_rle = width >= MIN_RLE_WIDTH && width <= MAX_RLE_WIDTH &&
+        head[0] == 2 && head[1] == 2 && head[2] & 0x80 == 0;
+    if !is_rle {
+        return decode_flat_scanline(data, pos, width, out_scanline);
+    }
+
+    let encoded_width = ((head[2] as usize) << 8) | head[3] as usize;
+    if encoded_width != width {
+        return Err(format!("scanline width {} differs from image width {}.", encoded_width, width));
+    }
+    *pos += 4;
+
+    out_scanline.resize(width, [0, 0, 0, 0]);
+    for c in 0..4 {
+        let mut col: usize = 0;
+        while col < width {
+            let count = *data.get(*pos).ok_or("truncated data.")? as usize;
+            *pos += 1;
+            if count > 128 {
+                // A run of the same value.
+                let count = count - 128;
+                let value = *data.get(*pos).ok_or("truncated data.")?;
+                *pos += 1;
+                if count == 0 || col + count > width {
+                    return Err(String::from("bad run length."));
+                }
+                for i in 0..count {
+                    out_scanline[col + i][c] = value;
+                }
+                col += count;
+            } else {
+                // A run of literal values.
+                if count == 0 || col + count > width {
+                    return Err(String::from("bad literal length."));
+                }
+                let values = data.get(*pos..*pos + count).ok_or("truncated data.")?;
+                for i in 0..count {
+                    out_scanline[col + i][c] = values[i];
+                }
+                *pos += count;
+                col += count;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Uncompressed pixels, possibly with the run-length encoding of
+// older files, where a (1, 1, 1, n) pixel repeats the previous one.
+// Consecutive markers give the higher bytes of the count, so that
+// more than three of them can not describe a valid scanline.
+fn decode_flat_scanline(data: &[u8],
+                        pos: &mut usize,
+                        width: usize,
+                        out_scanline: &mut Vec<[u8; 4]>) -> Result<(), String> {
+    let mut shift: u32 = 0;
+    while out_scanline.len() < width {
+        let pixel = data.get(*pos..*pos + 4).ok_or("truncated data.")?;
+        *pos += 4;
+        if pixel[0] == 1 && pixel[1] == 1 && pixel[2] == 1 {
+            let previous = *out_scanline.last().ok_or("run without a previous pixel.")?;
+            if shift >= 24 {
+                return Err(String::from("too many consecutive run markers."));
+            }
+            let count = (pixel[3] as usize) << shift;
+            if out_scanline.len() + count > width {
+                return Err(String::from("bad run length."));
+            }
+            for _i in 0..count {
+                out_scanline.push(previous);
+            }
+            shift += 8;
+        } else {
+            out_scanline.push([pixel[0], pixel[1], pixel[2], pixel[3]]);
+            shift = 0;
+        }
+    }
+
+    Ok(())
+}
+
+fn encode_scanline(scanline: &[[u8; 4]],
+                   out_data: &mut Vec<u8>) {
+    let width = scanline.len();
+    if width < MIN_RLE_WIDTH || width > MAX_RLE_WIDTH {
+        for pixel in scanline {
+            out_data.extend_from_slice(pixel);
+        }
+        return;
+    }
+
+    out_data.extend_from_slice(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8]);
+    let mut values: Vec<u8> = Vec::with_capacity(width);
+    for c in 0..4 {
+        values.clear();
+        values.extend(scanline.iter().map(|pixel| pixel[c]));
+        encode_channel(&values, out_data);
+    }
+}
+
+// Encode the values of one channel as runs of at most 127 equal
+// values and literals of at most 128 values.
+fn encode_channel(values: &[u8],
+                  out_data: &mut Vec<u8>) {
+    let length = values.len();
+    let mut cur: usize = 0;
+    while cur < length {
+        // Find the next run long enough to be worth encoding.
+        let mut run_start = cur;
+        let mut run_length: usize = 0;
+        while run_start < length {
+            run_length = 1;
+            while run_start + run_length < length && run_length < 127 &&
+                values[run_start + run_length] == values[run_start] {
+                run_length += 1;
+            }
+            if run_length >= MIN_RUN_LENGTH {
+                break;
+            }
+            run_start += run_length;
+        }
+
+        // Literal values before the run.
+        while cur < run_start {
+            let count = (run_start - cur).min(128);
+            out_data.push(count as u8);
+            out_data.extend_from_slice(&values[cur..cur + count]);
+            cur += count;
+        }
+
+        if run_start < length && run_length >= MIN_RUN_LENGTH {
+            out_data.push((128 + run_length) as u8);
+            out_data.push(values[run_start]);
+            cur = run_start + run_length;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(data: &[u8],
+              width: usize) -> Result<Vec<[u8; 4]>, String> {
+        let mut pos: usize = 0;
+        let mut scanline: Vec<[u8; 4]> = Vec::new();
+        decode_scanline(data, &mut pos, width, &mut scanline)?;
+        assert_eq!(pos, data.len());
+        Ok(scanline)
+    }
+
+    #[test]
+    fn rgbe_round_trip() {
+        for &(r, g, b) in &[(1.0, 0.5, 0.25), (1234.5, 0.1, 7.0), (1e-5, 3e-5, 2e-5), (0.0, 0.0, 0.75)] {
+            let (r2, g2, b2) = rgbe_to_float(float_to_rgbe(r, g, b));
+            // Mantissas have 8 bits relative to the largest channel.
+            let tolerance = r.max(g).max(b) / 128.0;
+            assert!((r - r2).abs() <= tolerance, "{} {}", r, r2);
+            assert!((g - g2).abs() <= tolerance, "{} {}", g, g2);
+            assert!((b - b2).abs() <= tolerance, "{} {}", b, b2);
+        }
+    }
+
+    #[test]
+    fn rgbe_clips_out_of_range_values() {
+        assert_eq!(float_to_rgbe(0.0, 0.0, 0.0), [0, 0, 0, 0]);
+        assert_eq!(float_to_rgbe(-1.0, -2.0, -3.0), [0, 0, 0, 0]);
+        assert_eq!(float_to_rgbe(f32::NAN, f32::NAN, f32::NAN), [0, 0, 0, 0]);
+        assert_eq!(float_to_rgbe(f32::INFINITY, 1.0, 1.0), [255, 255, 255, 255]);
+        assert_eq!(float_to_rgbe(f32::MAX, 0.0, 0.0), [255, 255, 255, 255]);
+        assert_eq!(rgbe_to_float([0, 0, 0, 0]), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn scanline_round_trip() {
+        // Runs, literals and runs longer than 127 values, and widths too
+        // small or too large for the run-length encoding.
+        for &width in &[3, 8, 300, MAX_RLE_WIDTH + 1] {
+            let scanline: Vec<[u8; 4]> = (0..width)
+                .map(|i| if i % 150 < 130 { [10, 20, 30, 128] } else { [i as u8, (i * 7) as u8, 3, 130] })
+                .collect();
+            let mut data: Vec<u8> = Vec::new();
+            encode_scanline(&scanline, &mut data);
+            if (MIN_RLE_WIDTH..=MAX_RLE_WIDTH).contains(&width) {
+                assert!(data.len() < width * 4);
+            }
+            assert_eq!(decode(&data, width).unwrap(), scanline);
+        }
+    }
+
+    #[test]
+    fn decodes_old_run_length_encoding() {
+        // One pixel repeated 3 then 2 * 256 more times.
+        let data: Vec<u8> = vec![5, 6, 7, 128, 1, 1, 1, 3, 1, 1, 1, 2];
+        let scanline = decode(&data, 4 + 2 * 256).unwrap();
+        assert!(scanline.iter().all(|pixel| *pixel == [5, 6, 7, 128]));
+    }
+
+    #[test]
+    fn rejects_malformed_scanlines() {
+        // Too many consecutive run markers would shift the count past usize.
+        let mut data: Vec<u8> = vec![5, 6, 7, 128];
+        for _i in 0..8 {
+            data.extend_from_slice(&[1, 1, 1, 0]);
+        }
+        assert!(decode(&data, 16).is_err());
+
+        // A run without a previous pixel and a run past the end of the line.
+        assert!(decode(&[1, 1, 1, 2], 16).is_err());
+        assert!(decode(&[5, 6, 7, 128, 1, 1, 1, 200], 16).is_err());
+
+        // A run-length encoded scanline of the wrong width, truncated data,
+        // and a literal past the end of the line.
+        assert!(decode(&[2, 2, 0, 9], 8).is_err());
+        assert!(decode(&[2, 2, 0, 8, 136], 8).is_err());
+        assert!(decode(&[2, 2, 0, 8, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0], 8).is_err());
+    }
+
+    #[test]
+    fn reads_header() {
+        let data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\nEXPOSURE=2\nEXPOSURE=0.5\nEXPOSURE= 3\n\n-Y 4 +X 6\n";
+        let mut pos: usize = 0;
+        assert_eq!(read_header(data, &mut pos), Ok((6, 4, 3.0)));
+        assert_eq!(pos, data.len());
+
+        for data in [&b"#?PFM\n\n-Y 4 +X 6\n"[..],
+                     &b"#?RADIANCE\nFORMAT=32-bit_rle_xyze\n\n-Y 4 +X 6\n"[..],
+                     &b"#?RADIANCE\nEXPOSURE=0\n\n-Y 4 +X 6\n"[..],
+                     &b"#?RADIANCE\n\n+Y 4 +X 6\n"[..],
+                     &b"#?RADIANCE\n\n-Y 0 +X 6\n"[..],
+                     &b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n"[..]].iter() {
+            let mut pos: usize = 0;
+            assert!(read_header(data, &mut pos).is_err(), "{:?}", String::from_utf8_lossy(data));
+        }
+    }
+}
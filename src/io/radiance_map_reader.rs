@@ -0,0 +1,53 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat};
+use opencv::imgcodecs::imread;
+use opencv::prelude::MatTrait;
+
+use crate::base::opencv_utils::load_pfm;
+use crate::error::HdrError;
+use crate::io::radiance_hdr;
+
+// Load a radiance map produced by any tool as the CV_32FC3 BGR Mat
+// expected by the tone mappers. Radiance .hdr files are decoded by
+// radiance_hdr, PFM files by opencv_utils and other files, e.g. OpenEXR, by opencv.
+// Grey images are expanded to 3 channels and alpha channels are dropped,
+// but 8-bit and 16-bit images are rejected since they are not radiance maps.
+pub fn load_radiance_map(path: &str,
+                         out_hdri: &mut Mat) -> Result<(), HdrError> {
+    log::trace!("Loading radiance map {}.", path);
+
+    let lower_path = path.to_lowercase();
+    if lower_path.ends_with(".hdr") || lower_path.ends_with(".pic") {
+        return radiance_hdr::read_hdr(path, out_hdri);
+    }
+
+    let image: Mat = if lower_path.ends_with(".pfm") {
+        let mut image: Mat = Mat::default()?;
+        load_pfm(path, &mut image)?;
+        image
+    } else {
+        imread(path, opencv::imgcodecs::IMREAD_ANYDEPTH | opencv::imgcodecs::IMREAD_ANYCOLOR)?
+    };
+    if image.empty()? {
+        return Err(HdrError::InvalidFormat(format!("{}: can not be decoded as an image.", path)));
+    }
+
+    let depth = image.depth()?;
+    if depth != opencv::core::CV_32F && depth != opencv::core::CV_64F {
+        return Err(HdrError::InvalidMatType { expected: opencv::core::CV_32FC3, actual: image.typ()? });
+    }
+
+    let mut float_image: Mat = Mat::default()?;
+    image.convert_to(&mut float_image, opencv::core::CV_32F, 1.0, 0.0)?;
+
+    match float_image.channels()? {
+        1 => opencv::imgproc::cvt_color(&float_image, out_hdri, opencv::imgproc::COLOR_GRAY2BGR, 0)?,
+        3 => *out_hdri = float_image,
+        4 => opencv::imgproc::cvt_color(&float_image, out_hdri, opencv::imgproc::COLOR_BGRA2BGR, 0)?,
+        channels => return Err(HdrError::InvalidFormat(
+            format!("{}: radiance maps need 1, 3 or 4 channels, got {}.", path, channels))),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,216 @@
+/* Copyright 2020 Yuchen Wong */
+
+use opencv::core::{Mat, Vec3f};
+use opencv::prelude::MatTrait;
+use rawloader::RawImageData;
+
+use crate::error::HdrError;
+
+// This file develops camera raw files (DNG, CR2, NEF, ARW, ...) decoded by
+// rawloader into linear images, keeping the data proportional to the
+// scene radiance so that brackets can be merged without a CRF.
+
+// Linear sRGB primaries to XYZ (D65).
+const RGB_TO_XYZ: [[f32; 3]; 3] = [
+    [0.412453, 0.357580, 0.180423],
+    [0.212671, 0.715160, 0.072169],
+    [0.019334, 0.119193, 0.950227],
+];
+
+// Sensor values, normalized to the white level, above which a sample is
+// considered saturated.
+pub const SATURATION_LEVEL: f32 = 0.95;
+
+// Decode a camera raw file into a CV_32FC3 BGR image in linear sRGB.
+// Pixels are scaled so that the sensor white level maps to 1, then white
+// balanced with the camera coefficients, demosaiced bilinearly and
+// converted with the camera color matrix.
+// Saturation is measured on the sensor values, before the white balance
+// and the color matrix move clipped samples below the white level.
+// out_saturation_mask is CV_8UC1, 255 where any sample a pixel is made of
+// is saturated.
+pub fn read_raw(path: &str,
+                out_image: &mut Mat,
+                out_saturation_mask: &mut Mat) -> Result<(), HdrError> {
+    log::trace!("Reading raw file {}.", path);
+
+    let raw = rawloader::decode_file(path)
+        .map_err(|e| HdrError::InvalidFormat(format!("{}: {:?}", path, e)))?;
+    log::info!("{}: {} {}, {}x{}, {} components per pixel.",
+               path, raw.clean_make, raw.clean_model, raw.width, raw.height, raw.cpp);
+    if raw.cpp != 1 && raw.cpp != 3 {
+        return Err(HdrError::InvalidFormat(
+            format!("{}: {} components per pixel are not supported.", path, raw.cpp)));
+    }
+
+    let width = raw.width;
+    let height = raw.height;
+    let data: Vec<f32> = match raw.data {
+        RawImageData::Integer(ref data) => data.iter().map(|v| *v as f32).collect(),
+        RawImageData::Float(ref data) => data.clone(),
+    };
+
+    // White balance relative to green, some cameras do not record it.
+    let mut wb_coeffs: [f32; 4] = [1.0; 4];
+    if raw.wb_coeffs[1].is_finite() && raw.wb_coeffs[1] > 0.0 {
+        for c in 0..4 {
+            if raw.wb_coeffs[c].is_finite() && raw.wb_coeffs[c] > 0.0 {
+                wb_coeffs[c] = raw.wb_coeffs[c] / raw.wb_coeffs[1];
+            }
+        }
+    }
+    // The fourth color of 4 color sensors is another green.
+    wb_coeffs[3] = wb_coeffs[1];
+
+    // Scale and white balance each sample, stored as RGB.
+    let mut rgb: Vec<[f32; 3]> = vec![[0.0; 3]; width * height];
+    let mut known: Vec<[bool; 3]> = vec![[false; 3]; width * height];
+    let mut saturated: Vec<[bool; 3]> = vec![[false; 3]; width * height];
+    for row in 0..height {
+        for col in 0..width {
+            let index = row * width + col;
+            if raw.cpp == 3 {
+                for c in 0..3 {
+                    let value = normalize(data[index * 3 + c], raw.blacklevels[c], raw.whitelevels[c]);
+                    rgb[index][c] = value * wb_coeffs[c];
+                    known[index][c] = true;
+                    saturated[index][c] = value > SATURATION_LEVEL;
+                }
+            } else {
+                let color = raw.cfa.color_at(row, col);
+                let value = normalize(data[index], raw.blacklevels[color], raw.whitelevels[color]);
+                let c = if color == 3 { 1 } else { color };
+                rgb[index][c] = value * wb_coeffs[color];
+                known[index][c] = true;
+                saturated[index][c] = value > SATURATION_LEVEL;
+            }
+        }
+    }
+
+    if raw.cpp == 1 {
+        demosaic_bilinear(width, height, &known, &mut rgb, &mut saturated);
+    }
+
+    let cam_to_rgb = compute_cam_to_rgb(&raw.xyz_to_cam);
+
+    // Crops are given as top, right, bottom, left.
+    let top = raw.crops[0].min(height);
+    let bottom = height - raw.crops[2].min(height - top);
+    let left = raw.crops[3].min(width);
+    let right = width - raw.crops[1].min(width - left);
+    if bottom <= top || right <= left {
+        return Err(HdrError::InvalidFormat(format!("{}: invalid crops {:?}.", path, raw.crops)));
+    }
+
+    unsafe {
+        out_image.create_rows_cols((bottom - top) as i32, (right - left) as i32, opencv::core::CV_32FC3)?;
+        out_saturation_mask.create_rows_cols((bottom - top) as i32, (right - left) as i32, opencv::core::CV_8UC1)?;
+    }
+    for row in top..bottom {
+        for col in left..right {
+            let cam = rgb[row * width + col];
+            let pixel = out_image.at_2d_mut::<Vec3f>((row - top) as i32, (col - left) as i32)?;
+            for c in 0..3 {
+                let value = cam_to_rgb[c][0] * cam[0] + cam_to_rgb[c][1] * cam[1] + cam_to_rgb[c][2] * cam[2];
+                // BGR order as the rest of hdr-rust.
+                pixel[2 - c] = value.max(0.0);
+            }
+            let is_saturated = saturated[row * width + col].iter().any(|s| *s);
+            *out_saturation_mask.at_2d_mut::<u8>((row - top) as i32, (col - left) as i32)? =
+                if is_saturated { 255 } else { 0 };
+        }
+    }
+
+    Ok(())
+}
+
+fn normalize(value: f32,
+             black_level: u16,
+             white_level: u16) -> f32 {
+    let range = white_level as f32 - black_level as f32;
+    if range <= 0.0 {
+        return 0.0;
+    }
+    ((value - black_level as f32) / range).max(0.0)
+}
+
+// Fill every missing color of a pixel with the mean of the samples of
+// this color in its 3x3 neighbourhood, which works for any CFA pattern.
+// An interpolated color is saturated when any of its samples is.
+fn demosaic_bilinear(width: usize,
+                     height: usize,
+                     known: &Vec<[bool; 3]>,
+                     rgb: &mut Vec<[f32; 3]>,
+                     saturated: &mut Vec<[bool; 3]>) {
+    let source: Vec<[f32; 3]> = rgb.clone();
+    for row in 0..height {
+        for col in 0..width {
+            let index = row * width + col;
+            for c in 0..3 {
+                if known[index][c] {
+                    continue;
+                }
+
+                let mut sum: f32 = 0.0;
+                let mut count: usize = 0;
+                for i in row.saturating_sub(1)..(row + 2).min(height) {
+                    for j in col.saturating_sub(1)..(col + 2).min(width) {
+                        if known[i * width + j][c] {
+                            sum += source[i * width + j][c];
+                            count += 1;
+                            if saturated[i * width + j][c] {
+                                saturated[index][c] = true;
+                            }
+                        }
+                    }
+                }
+                if count > 0 {
+                    rgb[index][c] = sum / count as f32;
+                }
+            }
+        }
+    }
+}
+
+// The camera to sRGB matrix, from the XYZ to camera matrix of the raw file,
+// with rows of sRGB to camera normalized so that white stays white.
+// Identity when the camera has no color matrix.
+fn compute_cam_to_rgb(xyz_to_cam: &[[f32; 3]; 4]) -> [[f32; 3]; 3] {
+    let identity: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    let mut rgb_to_cam: [[f32; 3]; 3] = [[0.0; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            for k in 0..3 {
+                rgb_to_cam[i][j] += xyz_to_cam[i][k] * RGB_TO_XYZ[k][j];
+            }
+        }
+        let sum: f32 = rgb_to_cam[i].iter().sum();
+        if sum.abs() < 1e-6 {
+            return identity;
+        }
+        for j in 0..3 {
+            rgb_to_cam[i][j] /= sum;
+        }
+    }
+
+    let m = &rgb_to_cam;
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) -
+              m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) +
+              m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det.abs() < 1e-6 {
+        return identity;
+    }
+
+    let mut inverse: [[f32; 3]; 3] = [[0.0; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            // Cofactor of m[j][i] divided by the determinant.
+            let (r0, r1) = ((j + 1) % 3, (j + 2) % 3);
+            let (c0, c1) = ((i + 1) % 3, (i + 2) % 3);
+            inverse[i][j] = (m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]) / det;
+        }
+    }
+
+    inverse
+}
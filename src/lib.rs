@@ -0,0 +1,11 @@
+/* Copyright 2020 Yuchen Wong */
+
+//! hdr-rust provides different methods of HDR construction,
+//! tone-mapping and panorama stitching on top of opencv.
+
+extern crate log;
+
+pub mod base;
+pub mod core;
+pub mod error;
+pub mod io;
@@ -1,111 +1,829 @@
+extern crate clap;
 extern crate pretty_env_logger;
 extern crate log;
 
-use opencv::core::{Mat, Point, Scalar};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use opencv::core::{Mat, Point};
 use opencv::imgcodecs::{imread, imwrite};
-use opencv::prelude::Vector;
+use opencv::prelude::{MatTrait, Vector};
 use opencv::types::{VectorOfMat, VectorOfi32};
 
 use std::error::Error;
+use std::str::FromStr;
 
-#[path = "./base/math_utils.rs"] mod math_utils;
-#[path = "./base/opencv_utils.rs"] mod opencv_utils;
-#[path = "./core/cylindrical_image_wrapper.rs"] mod cy_wrap;
-#[path = "./core/debevec_crf_solver.rs"] mod debevec_crf;
-#[path = "./core/default_feature_matcher.rs"] mod default_feature_matcher;
-#[path = "./core/default_image_blender.rs"] mod image_blender;
-#[path = "./core/default_image_matcher.rs"] mod image_matcher;
-#[path = "./core/harris_corner_detector.rs"] mod harris_corner_detector;
-#[path = "./core/mtb_image_alignment.rs"] mod mtb;
-#[path = "./core/photographic_global_tone_mapping.rs"] mod global_tone_mapping;
-#[path = "./core/photographic_local_tone_mapping.rs"] mod local_tone_mapping;
-#[path = "./core/sift_feature_descriptor.rs"] mod sift;
+use hdr_rust::base::opencv_utils;
+use hdr_rust::core::alignment::ecc_image_alignment as ecc;
+use hdr_rust::core::alignment::mtb_image_alignment as mtb;
+use hdr_rust::core::crf::camera_response::CameraResponse;
+use hdr_rust::core::crf::debevec_crf_solver as debevec_crf;
+use hdr_rust::core::crf::mitsunaga_nayar_crf_solver as mitsunaga_nayar_crf;
+use hdr_rust::core::crf::radiance_map_merger;
+use hdr_rust::core::crf::robertson_crf_solver as robertson_crf;
+use hdr_rust::core::fusion::mertens_exposure_fusion as mertens_fusion;
+use hdr_rust::core::panorama::cylindrical_image_wrapper as cy_wrap;
+use hdr_rust::core::panorama::default_feature_matcher;
+use hdr_rust::core::panorama::default_image_blender as image_blender;
+use hdr_rust::core::panorama::default_image_matcher as image_matcher;
+use hdr_rust::core::panorama::harris_corner_detector;
+use hdr_rust::core::panorama::sift_feature_descriptor as sift;
+use hdr_rust::core::tonemap::display_adaptive_tone_mapping;
+use hdr_rust::core::tonemap::display_adaptive_tone_mapping::DisplayModel;
+use hdr_rust::core::tonemap::drago_logarithmic_tone_mapping as drago_tone_mapping;
+use hdr_rust::core::tonemap::durand_bilateral_tone_mapping as durand_tone_mapping;
+use hdr_rust::core::tonemap::fattal_gradient_tone_mapping as fattal_tone_mapping;
+use hdr_rust::core::tonemap::photographic_global_tone_mapping as global_tone_mapping;
+use hdr_rust::core::tonemap::photographic_local_tone_mapping as local_tone_mapping;
+use hdr_rust::io::exif_reader;
+use hdr_rust::io::exr_writer;
+use hdr_rust::io::exr_writer::{ExrAttribute, ExrCompression, ExrOptions, ExrPixelType};
+use hdr_rust::io::radiance_hdr;
+use hdr_rust::io::radiance_map_reader;
+use hdr_rust::io::raw_reader;
 
 fn main() -> Result<(), Box<dyn Error>> {
     pretty_env_logger::init();
     log::trace!("HDR-Rust Starts.");
 
-    let image1: Mat = imread("/Users/apple/Pictures/parrington/prtn02.jpg", 1)?;
-    let image2: Mat = imread("/Users/apple/Pictures/parrington/prtn01.jpg", 1)?;
-    let image3: Mat = imread("/Users/apple/Pictures/parrington/prtn00.jpg", 1)?;
-    let mut dst1: Mat = Mat::default()?;
-    let mut indicies1: Mat = Mat::default()?;
-    cy_wrap::cylindrial_wrap(&image1, 705.849, &mut dst1, &mut indicies1).unwrap();
-    let mut dst2 = Mat::default()?;
-    let mut indicies2: Mat = Mat::default()?;
-    cy_wrap::cylindrial_wrap(&image2, 706.286, &mut dst2, &mut indicies2).unwrap();
-    let mut dst3 = Mat::default()?;
-    let mut indicies3: Mat = Mat::default()?;
-    cy_wrap::cylindrial_wrap(&image3, 704.916, &mut dst3, &mut indicies3).unwrap();
-    let out_features1 = harris_corner_detector::harris_detect_corner(&dst1, 3, 0.04, 72.0, true).unwrap();
-    let out_features2 = harris_corner_detector::harris_detect_corner(&dst2, 3, 0.04, 64.0, true).unwrap();
-    let out_features3 = harris_corner_detector::harris_detect_corner(&dst3, 3, 0.04, 64.0, true).unwrap();
-
-    let mut features1: Mat = Mat::default()?;
-    sift::sift_feature_description(&dst1, &out_features1, &mut features1).unwrap();
-    let mut features2: Mat = Mat::default()?;
-    sift::sift_feature_description(&dst2, &out_features2, &mut features2).unwrap();
-    let mut features3: Mat = Mat::default()?;
-    sift::sift_feature_description(&dst3, &out_features3, &mut features3).unwrap();
-    let feature_match1 = default_feature_matcher::match_feature(&features1, &features2, 0.6).unwrap();
-    let feature_match2 = default_feature_matcher::match_feature(&features2, &features3, 0.7).unwrap();
-
-    let m1 = image_matcher::match_image(&mut dst1, &mut dst2, &out_features1, &out_features2, &feature_match1).unwrap();
-    let m2 = image_matcher::match_image(&mut dst2, &mut dst3, &out_features2, &out_features3, &feature_match2).unwrap();
+    let matches = build_cli().get_matches();
+    match matches.subcommand() {
+        ("hdr", Some(hdr_matches)) => match hdr_matches.subcommand() {
+            ("merge", Some(sub_matches)) => run_hdr_merge(sub_matches)?,
+            ("fuse", Some(sub_matches)) => run_hdr_fuse(sub_matches)?,
+            ("tonemap", Some(sub_matches)) => run_hdr_tonemap(sub_matches)?,
+            _ => unreachable!(),
+        },
+        ("panorama", Some(panorama_matches)) => match panorama_matches.subcommand() {
+            ("stitch", Some(sub_matches)) => run_panorama_stitch(sub_matches)?,
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    }
+
+    log::trace!("HDR-Rust ends.");
+    Ok(())
+}
+
+fn build_cli() -> App<'static, 'static> {
+    App::new("hdr-rust")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("HDR construction, tone mapping and panorama stitching.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("hdr")
+            .about("High dynamic range imaging.")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("merge")
+                .about("Aligns a bracket with MTB and merges it into a radiance map with Debevec's method.")
+                .arg(Arg::with_name("inputs")
+                     .help("Input LDR images of the bracket, 8-bit or 16-bit, or camera raw files merged linearly without CRF.")
+                     .required(true)
+                     .multiple(true)
+                     .min_values(2))
+                .arg(Arg::with_name("exposures")
+                     .long("exposures")
+                     .short("e")
+                     .help("Comma separated exposure times in seconds, one per input image. Read from EXIF when omitted. Rough estimates are enough for the Mitsunaga-Nayar solver.")
+                     .takes_value(true)
+                     .multiple(true)
+                     .require_delimiter(true))
+                .arg(Arg::with_name("output")
+                     .long("output")
+                     .short("o")
+                     .help("Output radiance map (.exr, .hdr or .pfm).")
+                     .takes_value(true)
+                     .required(true))
+                .args(&alignment_args())
+                .arg(Arg::with_name("solver")
+                     .long("solver")
+                     .help("Camera response recovery method.")
+                     .takes_value(true)
+                     .possible_values(&["debevec", "robertson", "mitsunaga-nayar"])
+                     .default_value("debevec"))
+                .arg(Arg::with_name("iterations")
+                     .long("iterations")
+                     .help("Maximum iterations of the Robertson and Mitsunaga-Nayar solvers.")
+                     .takes_value(true)
+                     .default_value("30"))
+                .arg(Arg::with_name("threshold")
+                     .long("threshold")
+                     .help("Convergence threshold of the Robertson and Mitsunaga-Nayar solvers.")
+                     .takes_value(true)
+                     .default_value("0.00001"))
+                .arg(Arg::with_name("degree")
+                     .long("degree")
+                     .help("Degree of the response polynomial of the Mitsunaga-Nayar solver.")
+                     .takes_value(true)
+                     .default_value("3"))
+                .arg(Arg::with_name("samples")
+                     .long("samples")
+                     .help("Number of pixels sampled for the Debevec and Mitsunaga-Nayar solvers.")
+                     .takes_value(true)
+                     .default_value("512"))
+                .arg(Arg::with_name("lambda")
+                     .long("lambda")
+                     .help("Smoothness weight of the Debevec solver.")
+                     .takes_value(true)
+                     .default_value("0.7"))
+                .arg(Arg::with_name("response_output")
+                     .long("response-output")
+                     .help("Save the recovered camera response curve as a csv file.")
+                     .takes_value(true))
+                .arg(Arg::with_name("response")
+                     .long("response")
+                     .help("Merge with a camera response curve saved before instead of solving it.")
+                     .takes_value(true)
+                     .conflicts_with_all(&["linear", "response_output"]))
+                .arg(Arg::with_name("linear")
+                     .long("linear")
+                     .help("Merge assuming a linear camera response, e.g. for images developed from RAW.")
+                     .conflicts_with("response_output"))
+                .arg(Arg::with_name("exr_pixel_type")
+                     .long("exr-pixel-type")
+                     .help("Pixel type of .exr outputs.")
+                     .takes_value(true)
+                     .possible_values(&["half", "float"])
+                     .default_value("float"))
+                .arg(Arg::with_name("exr_compression")
+                     .long("exr-compression")
+                     .help("Compression of .exr outputs.")
+                     .takes_value(true)
+                     .possible_values(&["none", "rle", "zip", "piz"])
+                     .default_value("zip"))
+                .arg(Arg::with_name("deghost")
+                     .long("deghost")
+                     .help("Drop pixels inconsistent with the reference exposure from the merge, removing ghosts of moving objects."))
+                .arg(Arg::with_name("ghost_reference")
+                     .long("ghost-reference")
+                     .help("Index of the reference image of ghost removal, the middle image by default.")
+                     .takes_value(true)
+                     .requires("deghost"))
+                .arg(Arg::with_name("ghost_threshold")
+                     .long("ghost-threshold")
+                     .help("Largest log radiance difference to the reference before a pixel is a ghost.")
+                     .takes_value(true)
+                     .default_value("0.5"))
+                .arg(Arg::with_name("ghost_mask_output")
+                     .long("ghost-mask-output")
+                     .help("Save the mask of detected ghosts as an image.")
+                     .takes_value(true)
+                     .requires("deghost")))
+            .subcommand(SubCommand::with_name("fuse")
+                .about("Aligns a bracket with MTB and fuses it into an 8-bit image with Mertens' exposure fusion.")
+                .arg(Arg::with_name("inputs")
+                     .help("Input LDR images of the bracket.")
+                     .required(true)
+                     .multiple(true)
+                     .min_values(2))
+                .arg(Arg::with_name("output")
+                     .long("output")
+                     .short("o")
+                     .help("Output LDR image.")
+                     .takes_value(true)
+                     .required(true))
+                .args(&alignment_args())
+                .arg(Arg::with_name("contrast")
+                     .long("contrast")
+                     .help("Exponent of the contrast weight.")
+                     .takes_value(true)
+                     .default_value("1.0"))
+                .arg(Arg::with_name("saturation")
+                     .long("saturation")
+                     .help("Exponent of the saturation weight.")
+                     .takes_value(true)
+                     .default_value("1.0"))
+                .arg(Arg::with_name("exposedness")
+                     .long("exposedness")
+                     .help("Exponent of the well-exposedness weight.")
+                     .takes_value(true)
+                     .default_value("1.0")))
+            .subcommand(SubCommand::with_name("tonemap")
+                .about("Tone maps a radiance map into an 8-bit image.")
+                .arg(Arg::with_name("input")
+                     .help("Input radiance map (.exr, .hdr or .pfm).")
+                     .required(true))
+                .arg(Arg::with_name("output")
+                     .long("output")
+                     .short("o")
+                     .help("Output LDR image.")
+                     .takes_value(true)
+                     .required(true))
+                .arg(Arg::with_name("method")
+                     .long("method")
+                     .short("m")
+                     .help("Tone mapping operator.")
+                     .takes_value(true)
+                     .possible_values(&["global", "local", "durand", "fattal", "drago", "display"])
+                     .default_value("global"))
+                .arg(Arg::with_name("key")
+                     .long("key")
+                     .help("Key value a (global) or alpha (local), or auto to estimate it from the log luminance range.")
+                     .takes_value(true)
+                     .default_value("0.18"))
+                .arg(Arg::with_name("l_white")
+                     .long("l-white")
+                     .help("Smallest luminance mapped to pure white, or auto to estimate it from the dynamic range (global).")
+                     .takes_value(true)
+                     .default_value("1.5"))
+                .arg(Arg::with_name("phi")
+                     .long("phi")
+                     .help("Sharpening parameter (local).")
+                     .takes_value(true)
+                     .default_value("8.0"))
+                .arg(Arg::with_name("epsilon")
+                     .long("epsilon")
+                     .help("Threshold for the scale selection (local).")
+                     .takes_value(true)
+                     .default_value("0.05"))
+                .arg(Arg::with_name("max_kernel_size")
+                     .long("max-kernel-size")
+                     .help("Largest gaussian kernel used by the scale selection (local).")
+                     .takes_value(true)
+                     .default_value("43"))
+                .arg(Arg::with_name("contrast")
+                     .long("contrast")
+                     .help("Target contrast of the base layer (durand).")
+                     .takes_value(true)
+                     .default_value("5.0"))
+                .arg(Arg::with_name("detail_gain")
+                     .long("detail-gain")
+                     .help("Gain applied to the detail layer (durand).")
+                     .takes_value(true)
+                     .default_value("1.0"))
+                .arg(Arg::with_name("sigma_space")
+                     .long("sigma-space")
+                     .help("Spatial sigma of the bilateral filter in pixels, 2% of the image size by default (durand).")
+                     .takes_value(true))
+                .arg(Arg::with_name("sigma_range")
+                     .long("sigma-range")
+                     .help("Range sigma of the bilateral filter in log10 luminance (durand).")
+                     .takes_value(true)
+                     .default_value("0.4"))
+                .arg(Arg::with_name("alpha")
+                     .long("alpha")
+                     .help("Gradients above alpha times the average gradient are attenuated (fattal).")
+                     .takes_value(true)
+                     .default_value("0.1"))
+                .arg(Arg::with_name("beta")
+                     .long("beta")
+                     .help("Attenuation exponent of large gradients, lower compresses more (fattal).")
+                     .takes_value(true)
+                     .default_value("0.85"))
+                .arg(Arg::with_name("saturation")
+                     .long("saturation")
+                     .help("Color saturation of the tone mapped image, 0.6 by default (fattal) or 1.0 (display).")
+                     .takes_value(true))
+                .arg(Arg::with_name("bias")
+                     .long("bias")
+                     .help("Bias of the logarithm bases, lower values brighten the shadows (drago).")
+                     .takes_value(true)
+                     .default_value("0.85"))
+                .arg(Arg::with_name("peak_luminance")
+                     .long("peak-luminance")
+                     .help("Peak luminance of the target display in cd/m^2 (display).")
+                     .takes_value(true)
+                     .default_value("200"))
+                .arg(Arg::with_name("black_level")
+                     .long("black-level")
+                     .help("Black level of the target display in cd/m^2 (display).")
+                     .takes_value(true)
+                     .default_value("0.8"))
+                .arg(Arg::with_name("ambient")
+                     .long("ambient")
+                     .help("Ambient illuminance around the display in lux (display).")
+                     .takes_value(true)
+                     .default_value("400"))))
+        .subcommand(SubCommand::with_name("panorama")
+            .about("Panorama construction.")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("stitch")
+                .about("Stitches images taken from left to right into a cylindrical panorama.")
+                .arg(Arg::with_name("inputs")
+                     .help("Input images, ordered from left to right.")
+                     .required(true)
+                     .multiple(true)
+                     .min_values(2))
+                .arg(Arg::with_name("focal_lengths")
+                     .long("focal-lengths")
+                     .short("f")
+                     .help("Comma separated focal lengths in pixels, one per input image.")
+                     .takes_value(true)
+                     .multiple(true)
+                     .require_delimiter(true)
+                     .required(true))
+                .arg(Arg::with_name("output")
+                     .long("output")
+                     .short("o")
+                     .help("Output panorama.")
+                     .takes_value(true)
+                     .required(true))
+                .arg(Arg::with_name("block_size")
+                     .long("block-size")
+                     .help("Window size of the harris corner detector.")
+                     .takes_value(true)
+                     .default_value("3"))
+                .arg(Arg::with_name("harris_k")
+                     .long("harris-k")
+                     .help("Empirical constant k of the harris response.")
+                     .takes_value(true)
+                     .default_value("0.04"))
+                .arg(Arg::with_name("harris_threshold")
+                     .long("harris-threshold")
+                     .help("Threshold on the normalized harris response.")
+                     .takes_value(true)
+                     .default_value("64.0"))
+                .arg(Arg::with_name("match_threshold")
+                     .long("match-threshold")
+                     .help("Ratio threshold between the best and second best feature match.")
+                     .takes_value(true)
+                     .default_value("0.7"))))
+}
+
+// Arguments of the bracket alignment shared by the hdr subcommands.
+fn alignment_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("align_level")
+            .long("align-level")
+            .help("Pyramid levels used by the alignment, 0 disables alignment.")
+            .takes_value(true)
+            .default_value("8"),
+        Arg::with_name("align_method")
+            .long("align-method")
+            .help("Alignment method, MTB searches integer translations, ECC also estimates rotations and perspective.")
+            .takes_value(true)
+            .possible_values(&["mtb", "ecc"])
+            .default_value("mtb"),
+        Arg::with_name("motion")
+            .long("motion")
+            .help("Motion model of the ECC alignment.")
+            .takes_value(true)
+            .possible_values(&["translation", "euclidean", "affine", "homography"])
+            .default_value("euclidean"),
+        Arg::with_name("align_iterations")
+            .long("align-iterations")
+            .help("Maximum iterations of the ECC alignment per pyramid level.")
+            .takes_value(true)
+            .default_value("100"),
+        Arg::with_name("align_epsilon")
+            .long("align-epsilon")
+            .help("Convergence threshold of the ECC alignment.")
+            .takes_value(true)
+            .default_value("0.00001"),
+        Arg::with_name("align_reference")
+            .long("align-reference")
+            .help("Index of the image the alignment aligns onto, or auto for the sharpest well exposed one. The middle image by default.")
+            .takes_value(true),
+        Arg::with_name("exclusion_threshold")
+            .long("exclusion-threshold")
+            .help("Noise tolerance of the MTB alignment around the median, in 8-bit levels.")
+            .takes_value(true)
+            .default_value("4"),
+        Arg::with_name("search_radius")
+            .long("search-radius")
+            .help("Offsets searched by the MTB alignment at each pyramid level.")
+            .takes_value(true)
+            .default_value("1"),
+        Arg::with_name("no_crop")
+            .long("no-crop")
+            .help("Keep the black borders of the aligned images instead of cropping them to their common area."),
+    ]
+}
+
+fn run_hdr_merge(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let inputs: Vec<&str> = matches.values_of("inputs").unwrap().collect();
+    let mut shutter_speeds: Vec<f32> = if matches.is_present("exposures") {
+        parse_values(matches, "exposures")?
+    } else {
+        exif_reader::read_shutter_speeds(&inputs)?
+    };
+    if shutter_speeds.len() != inputs.len() {
+        return Err(format!("Got {} exposures for {} images.", shutter_speeds.len(), inputs.len()).into());
+    }
+
+    if inputs.iter().all(|input| is_raw_file(input)) {
+        return run_hdr_merge_raw(matches, &inputs, &shutter_speeds);
+    }
+
+    let images: VectorOfMat = read_images(&inputs)?;
+    let aligned_images: VectorOfMat = align_bracket(matches, images)?;
+
+    let deghost: bool = matches.is_present("deghost");
+    let mut out_hdri: Mat = Mat::default()?;
+    let response = if let Some(response_path) = matches.value_of("response") {
+        log::trace!("Starting merging with camera response {}.", response_path);
+        CameraResponse::load(response_path)?
+    } else if matches.is_present("linear") {
+        log::trace!("Starting merging with linear camera response.");
+        CameraResponse::linear(opencv_utils::pixel_levels(&aligned_images.get(0)?)?)?
+    } else {
+        log::trace!("Starting solving CRF.");
+        let response = match matches.value_of("solver").unwrap() {
+            "robertson" => {
+                let max_iterations: i32 = parse_value(matches, "iterations")?;
+                let threshold: f32 = parse_value(matches, "threshold")?;
+                robertson_crf::solve(&aligned_images, &shutter_speeds, max_iterations, threshold, &mut out_hdri)?
+            },
+            "mitsunaga-nayar" => {
+                let degree: usize = parse_value(matches, "degree")?;
+                let sample_num: i32 = parse_value(matches, "samples")?;
+                let max_iterations: i32 = parse_value(matches, "iterations")?;
+                let threshold: f32 = parse_value(matches, "threshold")?;
+                let mut exposure_ratios: Vec<f32> = Vec::new();
+                let response = mitsunaga_nayar_crf::solve(&aligned_images, &shutter_speeds, degree, sample_num,
+                                                          max_iterations, threshold, &mut out_hdri, &mut exposure_ratios)?;
+                for (i, ratio) in exposure_ratios.iter().enumerate() {
+                    log::info!("Exposure ratio {} / {}: {}", inputs[i], inputs[i+1], ratio);
+                    // Later merges use the refined exposure times.
+                    shutter_speeds[i+1] = shutter_speeds[i] / ratio;
+                }
+                response
+            },
+            _ => {
+                let sample_num: i32 = parse_value(matches, "samples")?;
+                let lambda: f32 = parse_value(matches, "lambda")?;
+                debevec_crf::solve(&aligned_images, &shutter_speeds, sample_num, lambda, &mut out_hdri)?
+            },
+        };
+        if let Some(response_output) = matches.value_of("response_output") {
+            response.save(response_output)?;
+        }
+        response
+    };
+
+    if deghost {
+        log::trace!("Starting merging with ghost removal.");
+        let reference: usize = match matches.value_of("ghost_reference") {
+            Some(_) => parse_value(matches, "ghost_reference")?,
+            None => inputs.len() >> 1,
+        };
+        let threshold: f32 = parse_value(matches, "ghost_threshold")?;
+        let mut ghost_mask: Mat = Mat::default()?;
+        radiance_map_merger::merge_with_ghost_removal(&aligned_images, &shutter_speeds, &response,
+                                                      reference, threshold, &mut out_hdri, &mut ghost_mask)?;
+        if let Some(ghost_mask_output) = matches.value_of("ghost_mask_output") {
+            imwrite(ghost_mask_output, &ghost_mask, &VectorOfi32::new())?;
+        }
+    } else if out_hdri.empty()? {
+        radiance_map_merger::merge(&aligned_images, &shutter_speeds, &response, &mut out_hdri)?;
+    }
+
+    log::trace!("Starting output images.");
+    // Keep how the radiance map was made in the EXR header.
+    let mut exr_options = parse_exr_options(matches);
+    exr_options.attributes.push((String::from("exposureTimes"), ExrAttribute::Text(join_values(&shutter_speeds))));
+    // 16-bit curves have 65536 values per channel, 256 samples are enough
+    // to describe the response without bloating the header.
+    let header_response = if response.levels() > 256 { response.resample(256)? } else { response };
+    for (c, name) in ["responseCurveB", "responseCurveG", "responseCurveR"].iter().enumerate() {
+        exr_options.attributes.push((String::from(*name), ExrAttribute::Text(join_values(header_response.curve(c)))));
+    }
+    save_radiance_map(matches.value_of("output").unwrap(), &out_hdri, &exr_options)?;
+
+    Ok(())
+}
+
+// Raw brackets are already linear, so they are merged without any CRF.
+fn run_hdr_merge_raw(matches: &ArgMatches,
+                     inputs: &Vec<&str>,
+                     shutter_speeds: &Vec<f32>) -> Result<(), Box<dyn Error>> {
+    // Options of the CRF merge mean nothing here, fail rather than ignore them.
+    for name in ["deghost", "response", "linear", "response_output"].iter() {
+        if matches.is_present(name) {
+            return Err(format!("--{} is not supported for raw inputs.", name.replace('_', "-")).into());
+        }
+    }
+    if matches.occurrences_of("solver") > 0 {
+        return Err("--solver is not supported for raw inputs, which are merged linearly.".into());
+    }
+
+    log::trace!("Starting developing raw files.");
+    let mut images: VectorOfMat = VectorOfMat::new();
+    let mut saturation_masks: VectorOfMat = VectorOfMat::new();
+    for input in inputs {
+        let mut image: Mat = Mat::default()?;
+        let mut saturation_mask: Mat = Mat::default()?;
+        raw_reader::read_raw(input, &mut image, &mut saturation_mask)?;
+        images.push(image);
+        saturation_masks.push(saturation_mask);
+    }
+
+    let align_level: u8 = parse_value(matches, "align_level")?;
+    let (linear_images, linear_masks): (VectorOfMat, VectorOfMat) = if align_level > 0 {
+        // Alignment is estimated on integer images, 16 bits keep the precision
+        // of raw data, then applied to the linear images themselves.
+        let mut images_16u: VectorOfMat = VectorOfMat::new();
+        for i in 0..images.len() {
+            let mut image_16u: Mat = Mat::default()?;
+            images.get(i)?.convert_to(&mut image_16u, opencv::core::CV_16UC3, 65535.0, 0.0)?;
+            images_16u.push(image_16u);
+        }
+        let transforms: Vec<Mat> = estimate_alignment(matches, &images_16u)?;
+        let mut aligned_images = VectorOfMat::new();
+        apply_alignment(matches, &images, &transforms, &mut aligned_images)?;
+        // The masks are warped and cropped the same way as their images.
+        let mut aligned_masks = VectorOfMat::new();
+        apply_alignment(matches, &saturation_masks, &transforms, &mut aligned_masks)?;
+        (aligned_images, aligned_masks)
+    } else {
+        (images, saturation_masks)
+    };
+
+    log::trace!("Starting linear merging.");
+    let mut out_hdri: Mat = Mat::default()?;
+    radiance_map_merger::merge_linear(&linear_images, &linear_masks, shutter_speeds, &mut out_hdri)?;
+
+    let mut exr_options = parse_exr_options(matches);
+    exr_options.attributes.push((String::from("exposureTimes"), ExrAttribute::Text(join_values(shutter_speeds))));
+    save_radiance_map(matches.value_of("output").unwrap(), &out_hdri, &exr_options)?;
+
+    Ok(())
+}
+
+fn run_hdr_fuse(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let inputs: Vec<&str> = matches.values_of("inputs").unwrap().collect();
+    let contrast_weight: f32 = parse_value(matches, "contrast")?;
+    let saturation_weight: f32 = parse_value(matches, "saturation")?;
+    let exposedness_weight: f32 = parse_value(matches, "exposedness")?;
+
+    let images: VectorOfMat = read_images(&inputs)?;
+    let aligned_images: VectorOfMat = align_bracket(matches, images)?;
+
+    let mut out_ldr: Mat = Mat::default()?;
+    mertens_fusion::fuse(&aligned_images, contrast_weight, saturation_weight, exposedness_weight, &mut out_ldr)?;
+
+    imwrite(matches.value_of("output").unwrap(), &out_ldr, &VectorOfi32::new())?;
+    Ok(())
+}
+
+fn run_hdr_tonemap(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let input = matches.value_of("input").unwrap();
+    let mut src: Mat = Mat::default()?;
+    radiance_map_reader::load_radiance_map(input, &mut src)?;
+
+    // Reinhard's estimates, for batches exposed without per-image tuning.
+    let auto_parameters: Option<(f32, f32)> =
+        if matches.value_of("key") == Some("auto") || matches.value_of("l_white") == Some("auto") {
+            Some(global_tone_mapping::estimate_parameters(&src)?)
+        } else {
+            None
+        };
+    let key: f32 = match auto_parameters {
+        Some((key, _)) if matches.value_of("key") == Some("auto") => key,
+        _ => parse_value(matches, "key")?,
+    };
+    let mut out_ldr: Mat = Mat::default()?;
+    match matches.value_of("method").unwrap() {
+        "local" => {
+            let phi: f32 = parse_value(matches, "phi")?;
+            let epsilon: f32 = parse_value(matches, "epsilon")?;
+            let max_kernel_size: i32 = parse_value(matches, "max_kernel_size")?;
+            local_tone_mapping::map(&src, key, phi, epsilon, max_kernel_size, &mut out_ldr)?;
+        },
+        "durand" => {
+            let contrast: f32 = parse_value(matches, "contrast")?;
+            let detail_gain: f32 = parse_value(matches, "detail_gain")?;
+            let sigma_space: f32 = match matches.value_of("sigma_space") {
+                Some(_) => parse_value(matches, "sigma_space")?,
+                None => (0.02 * src.rows().max(src.cols()) as f32).max(1.0),
+            };
+            let sigma_range: f32 = parse_value(matches, "sigma_range")?;
+            durand_tone_mapping::map(&src, contrast, detail_gain, sigma_space, sigma_range, &mut out_ldr)?;
+        },
+        "fattal" => {
+            let alpha: f32 = parse_value(matches, "alpha")?;
+            let beta: f32 = parse_value(matches, "beta")?;
+            let saturation: f32 = match matches.value_of("saturation") {
+                Some(_) => parse_value(matches, "saturation")?,
+                None => 0.6,
+            };
+            fattal_tone_mapping::map(&src, alpha, beta, saturation, &mut out_ldr)?;
+        },
+        "display" => {
+            let mut display = DisplayModel::default();
+            display.peak_luminance = parse_value(matches, "peak_luminance")?;
+            display.black_level = parse_value(matches, "black_level")?;
+            display.ambient_illuminance = parse_value(matches, "ambient")?;
+            let saturation: f32 = match matches.value_of("saturation") {
+                Some(_) => parse_value(matches, "saturation")?,
+                None => 1.0,
+            };
+            display_adaptive_tone_mapping::map(&src, &display, saturation, &mut out_ldr)?;
+        },
+        "drago" => {
+            let bias: f32 = parse_value(matches, "bias")?;
+            drago_tone_mapping::map(&src, bias, &mut out_ldr)?;
+        },
+        _ => {
+            let l_white: f32 = match auto_parameters {
+                Some((_, l_white)) if matches.value_of("l_white") == Some("auto") => l_white,
+                _ => parse_value(matches, "l_white")?,
+            };
+            global_tone_mapping::map(&src, key, l_white, &mut out_ldr)?;
+        },
+    }
+
+    imwrite(matches.value_of("output").unwrap(), &out_ldr, &VectorOfi32::new())?;
+    Ok(())
+}
+
+fn run_panorama_stitch(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let inputs: Vec<&str> = matches.values_of("inputs").unwrap().collect();
+    let focal_lengths: Vec<f32> = parse_values(matches, "focal_lengths")?;
+    if focal_lengths.len() != inputs.len() {
+        return Err(format!("Got {} focal lengths for {} images.", focal_lengths.len(), inputs.len()).into());
+    }
+
+    let block_size: i32 = parse_value(matches, "block_size")?;
+    let harris_k: f64 = parse_value(matches, "harris_k")?;
+    let harris_threshold: f32 = parse_value(matches, "harris_threshold")?;
+    let match_threshold: f64 = parse_value(matches, "match_threshold")?;
 
     let mut images: Vec<Mat> = Vec::new();
-    images.push(dst1);
-    images.push(dst2);
-    images.push(dst3);
     let mut wrapped_image_indicies: Vec<Mat> = Vec::new();
-    wrapped_image_indicies.push(indicies1);
-    wrapped_image_indicies.push(indicies2);
-    wrapped_image_indicies.push(indicies3);
+    let mut image_features: Vec<Vec<Point>> = Vec::new();
+    let mut feature_descriptors: Vec<Mat> = Vec::new();
+    for (i, input) in inputs.iter().enumerate() {
+        let image: Mat = read_image(input, opencv::imgcodecs::IMREAD_COLOR)?;
+
+        let mut dst: Mat = Mat::default()?;
+        let mut indicies: Mat = Mat::default()?;
+        cy_wrap::cylindrial_wrap(&image, focal_lengths[i], &mut dst, &mut indicies)?;
+
+        let features = harris_corner_detector::harris_detect_corner(&dst, block_size, harris_k, harris_threshold, true)?;
+        let mut descriptors: Mat = Mat::default()?;
+        sift::sift_feature_description(&dst, &features, &mut descriptors)?;
+
+        images.push(dst);
+        wrapped_image_indicies.push(indicies);
+        image_features.push(features);
+        feature_descriptors.push(descriptors);
+    }
+
+    log::trace!("Sift feature extraction finished.");
+
     let mut alignments: Vec<Point> = Vec::new();
-    alignments.push(m1);
-    alignments.push(m2);
+    for i in 0..images.len()-1 {
+        let feature_match = default_feature_matcher::match_feature(
+            &feature_descriptors[i], &feature_descriptors[i+1], match_threshold)?;
+        let (left, right) = images.split_at_mut(i+1);
+        let alignment = image_matcher::match_image(&mut left[i], &mut right[0],
+                                                   &image_features[i], &image_features[i+1], &feature_match)?;
+        alignments.push(alignment);
+    }
 
     let mut panorama = Mat::default()?;
-    image_blender::blend_image(&images, &wrapped_image_indicies, &alignments, &mut panorama).unwrap();
+    image_blender::blend_image(&images, &wrapped_image_indicies, &alignments, &mut panorama)?;
 
-    log::trace!("Sift feature extraction finished.");
+    imwrite(matches.value_of("output").unwrap(), &panorama, &VectorOfi32::new())?;
+    Ok(())
+}
+
+fn is_radiance_hdr(path: &str) -> bool {
+    let path = path.to_lowercase();
+    path.ends_with(".hdr") || path.ends_with(".pic")
+}
+
+// Save a radiance map as Radiance .hdr, PFM or OpenEXR depending on the extension.
+fn save_radiance_map(path: &str,
+                     hdri: &Mat,
+                     exr_options: &ExrOptions) -> Result<(), Box<dyn Error>> {
+    if is_radiance_hdr(path) {
+        radiance_hdr::write_hdr(path, hdri)?;
+    } else if path.to_lowercase().ends_with(".pfm") {
+        opencv_utils::save_pfm(path, hdri, true)?;
+    } else {
+        exr_writer::save_exr(path, hdri, exr_options)?;
+    }
 
-     imwrite("/Users/apple/Desktop/panorma.jpg", &panorama, &VectorOfi32::new()).unwrap();
-    // imwrite("/home/yucwang/Desktop/cy.jpg", &dst, &VectorOfi32::new()).unwrap();
-    imwrite("/Users/apple/Desktop/harris_out1.jpg", &images[0], &VectorOfi32::new()).unwrap();
-    imwrite("/Users/apple/Desktop/harris_out2.jpg", &images[1], &VectorOfi32::new()).unwrap();
-    imwrite("/Users/apple/Desktop/harris_out3.jpg", &images[2], &VectorOfi32::new()).unwrap();
-    //harris_corner_detector::harris_detect_corner(&image, 3, 0.05, 108.0).unwrap();
-
-//    let mut images: VectorOfMat = VectorOfMat::new();
-//    let image1: Mat = imread("/home/yucwang/I_love_hefei_50/IMG_20200402_183757.jpg", 1)?;
-//    let image2: Mat = imread("/home/yucwang/I_love_hefei_50/IMG_20200402_183928.jpg", 1)?;
-//    let image3: Mat = imread("/home/yucwang/I_love_hefei_50/IMG_20200402_184101.jpg", 1)?;
-//    // let image4: Mat = imread("/home/yucwang/Pictures/i_love_hefei/i_love_hefei_3.jpg", 1)?;
-//    images.push(image1);
-//    images.push(image2);
-//    images.push(image3);
-//    // images.push(image4);
-//
-//    log::trace!("Starting align images.");
-//    let mut out_aligned_images = VectorOfMat::new();
-//    mtb::align(&images, &mut out_aligned_images, 8).unwrap();
-//
-//    images.clear();
-//
-//    log::trace!("Starting solving CRF.");
-//    let mut out_hdri: Mat = Mat::default()?;
-//    let mut shutter_speeds: Vec<f32> = Vec::new();
-//    shutter_speeds.push(0.0182);
-//    shutter_speeds.push(0.0667);
-//    shutter_speeds.push(1.0);
-//    // shutter_speeds.push(2.0);
-//
-//    debevec_crf::solve(&out_aligned_images, &shutter_speeds, 512, 0.7, &mut out_hdri).unwrap();
-//
-//    log::trace!("Starting output images.");
-//    opencv_utils::save_exr_with_default(&std::string::String::from("/home/yucwang/Desktop/i_love_hefei_hdr.exr"), &out_hdri)?;
-//
-//    log::trace!("HDR-Rust ends.");
     Ok(())
 }
+
+// Align a bracket with the method chosen by alignment_args.
+fn align_bracket(matches: &ArgMatches,
+                 images: VectorOfMat) -> Result<VectorOfMat, Box<dyn Error>> {
+    let align_level: u8 = parse_value(matches, "align_level")?;
+    if align_level == 0 {
+        return Ok(images);
+    }
+
+    let transforms: Vec<Mat> = estimate_alignment(matches, &images)?;
+    let mut aligned_images = VectorOfMat::new();
+    apply_alignment(matches, &images, &transforms, &mut aligned_images)?;
+
+    Ok(aligned_images)
+}
+
+fn estimate_alignment(matches: &ArgMatches,
+                      images: &VectorOfMat) -> Result<Vec<Mat>, Box<dyn Error>> {
+    log::trace!("Starting align images.");
+    let align_level: u8 = parse_value(matches, "align_level")?;
+    let reference = match matches.value_of("align_reference") {
+        Some("auto") => mtb::Reference::Auto,
+        Some(_) => mtb::Reference::Index(parse_value(matches, "align_reference")?),
+        None => mtb::Reference::Middle,
+    };
+    let mut transforms: Vec<Mat> = Vec::new();
+    match matches.value_of("align_method").unwrap() {
+        "ecc" => {
+            let motion_model = match matches.value_of("motion").unwrap() {
+                "translation" => ecc::MotionModel::Translation,
+                "affine" => ecc::MotionModel::Affine,
+                "homography" => ecc::MotionModel::Homography,
+                _ => ecc::MotionModel::Euclidean,
+            };
+            let max_iterations: i32 = parse_value(matches, "align_iterations")?;
+            let epsilon: f64 = parse_value(matches, "align_epsilon")?;
+            ecc::estimate_transforms(images, reference, motion_model, align_level, max_iterations, epsilon,
+                                     &mut transforms)?;
+        },
+        _ => {
+            let exclusion_threshold: u8 = parse_value(matches, "exclusion_threshold")?;
+            let search_radius: i32 = parse_value(matches, "search_radius")?;
+            mtb::estimate_transforms(images, reference, align_level, exclusion_threshold, search_radius,
+                                     &mut transforms)?
+        },
+    }
+
+    Ok(transforms)
+}
+
+// Aligned images are cropped to the area every image covers unless
+// --no-crop is given.
+fn apply_alignment(matches: &ArgMatches,
+                   images: &VectorOfMat,
+                   transforms: &Vec<Mat>,
+                   aligned_images: &mut VectorOfMat) -> Result<(), Box<dyn Error>> {
+    let crop: bool = !matches.is_present("no_crop");
+    match matches.value_of("align_method").unwrap() {
+        "ecc" if crop => { ecc::apply_transforms_and_crop(images, transforms, aligned_images)?; },
+        "ecc" => ecc::apply_transforms(images, transforms, aligned_images)?,
+        _ if crop => { mtb::apply_transforms_and_crop(images, transforms, aligned_images)?; },
+        _ => mtb::apply_transforms(images, transforms, aligned_images)?,
+    }
+
+    Ok(())
+}
+
+fn is_raw_file(path: &str) -> bool {
+    let path = path.to_lowercase();
+    [".dng", ".cr2", ".nef", ".arw", ".orf", ".rw2", ".pef", ".raf", ".srw"].iter()
+        .any(|extension| path.ends_with(extension))
+}
+
+fn parse_exr_options(matches: &ArgMatches) -> ExrOptions {
+    let mut exr_options = ExrOptions::default();
+    exr_options.pixel_type = match matches.value_of("exr_pixel_type").unwrap() {
+        "half" => ExrPixelType::Half,
+        _ => ExrPixelType::Float,
+    };
+    exr_options.compression = match matches.value_of("exr_compression").unwrap() {
+        "none" => ExrCompression::None,
+        "rle" => ExrCompression::Rle,
+        "piz" => ExrCompression::Piz,
+        _ => ExrCompression::Zip,
+    };
+
+    exr_options
+}
+
+fn join_values(values: &[f32]) -> String {
+    values.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(",")
+}
+
+fn read_image(path: &str,
+              flags: i32) -> Result<Mat, Box<dyn Error>> {
+    let image: Mat = imread(path, flags)?;
+    if image.empty()? {
+        return Err(format!("Failed to read image {}.", path).into());
+    }
+
+    Ok(image)
+}
+
+// Read the images of a bracket, keeping the precision of 16-bit files.
+fn read_images(paths: &Vec<&str>) -> Result<VectorOfMat, Box<dyn Error>> {
+    let mut images: VectorOfMat = VectorOfMat::new();
+    for path in paths {
+        images.push(read_image(path, opencv::imgcodecs::IMREAD_ANYDEPTH | opencv::imgcodecs::IMREAD_COLOR)?);
+    }
+
+    Ok(images)
+}
+
+fn parse_value<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<T, Box<dyn Error>> {
+    let raw_value = matches.value_of(name).unwrap();
+    match raw_value.parse::<T>() {
+        Ok(value) => Ok(value),
+        Err(_) => Err(format!("Invalid value {} for {}.", raw_value, name).into()),
+    }
+}
+
+fn parse_values<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Vec<T>, Box<dyn Error>> {
+    let mut values: Vec<T> = Vec::new();
+    for raw_value in matches.values_of(name).unwrap() {
+        match raw_value.parse::<T>() {
+            Ok(value) => values.push(value),
+            Err(_) => return Err(format!("Invalid value {} for {}.", raw_value, name).into()),
+        }
+    }
+
+    Ok(values)
+}